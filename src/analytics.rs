@@ -0,0 +1,171 @@
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use crate::clock::Clock;
+use crate::color::print_colored;
+use crate::portfolio::Portfolio;
+use crate::stock_listener::StockStore;
+use crate::traders::Trader;
+
+// A trader's account value marked to the latest prices at one point in simulated time
+#[derive(Debug, Clone, Serialize)]
+pub struct EquitySnapshot {
+    pub timestamp: u64,
+    pub equity: f64,
+}
+
+// Record the equity of every trader now. The marks are copied out first so the store is not
+// held while waiting on a trader, whose task may itself be waiting on a broker behind the store.
+pub async fn record_equity(traders: &[Arc<Mutex<Trader>>], stock_store: &StockStore, clock: &Clock) {
+    let timestamp = clock.now_millis();
+    let marks = stock_store.read().await.clone();
+    for trader in traders {
+        let mut trader = trader.lock().await;
+        let equity = trader.equity(&marks);
+        trader.equity_curve.push(EquitySnapshot { timestamp, equity });
+    }
+}
+
+// Snapshot every trader's equity at a fixed interval of simulated time for the whole session
+pub async fn run_equity_snapshots(traders: Vec<Arc<Mutex<Trader>>>, stock_store: StockStore, clock: Clock, interval: Duration) {
+    loop {
+        record_equity(&traders, &stock_store, &clock).await;
+        clock.sleep(interval).await;
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() { 0.0 } else { values.iter().sum::<f64>() / values.len() as f64 }
+}
+
+fn std_dev(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let mean = mean(values);
+    (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64).sqrt()
+}
+
+// Ratios are left at zero when the denominator is, a flat account has no risk to measure
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator > f64::EPSILON { numerator / denominator } else { 0.0 }
+}
+
+// Session performance of one trader from its equity curve and closed trades.
+// Ratios are annualised and assume a zero risk-free rate.
+#[derive(Debug, Clone, Serialize)]
+pub struct PerformanceReport {
+    pub trader_id: String,
+    pub returns: Vec<f64>, // Simple return between consecutive snapshots
+    pub total_return: f64,
+    pub volatility: f64,
+    pub sharpe_ratio: f64,
+    pub sortino_ratio: f64,
+    pub max_drawdown: f64, // Largest fall from a running peak, as a fraction of the peak
+    pub max_drawdown_duration_ms: u64, // Longest time spent below a previous peak
+    pub closed_trades: usize,
+    pub hit_rate: f64,
+    pub average_win: f64,
+    pub average_loss: f64,
+    pub profit_factor: Option<f64>, // Gross profit over gross loss, none without a losing trade
+}
+
+impl PerformanceReport {
+    // `periods_per_year` is how many snapshot intervals make up a year of model time
+    pub fn new(trader: &Trader, portfolio: &Portfolio, periods_per_year: f64) -> Self {
+        let curve = &trader.equity_curve;
+        let returns: Vec<f64> = curve.windows(2)
+            .map(|pair| ratio(pair[1].equity - pair[0].equity, pair[0].equity))
+            .collect();
+        let total_return = match (curve.first(), curve.last()) {
+            (Some(first), Some(last)) => ratio(last.equity - first.equity, first.equity),
+            _ => 0.0,
+        };
+
+        let annualisation = periods_per_year.sqrt();
+        let volatility = std_dev(&returns) * annualisation;
+        let sharpe_ratio = ratio(mean(&returns), std_dev(&returns)) * annualisation;
+        let downside = (returns.iter().map(|r| r.min(0.0).powi(2)).sum::<f64>() / returns.len().max(1) as f64).sqrt();
+        let sortino_ratio = ratio(mean(&returns), downside) * annualisation;
+
+        // Walk the curve tracking the running peak and when it was set
+        let mut max_drawdown = 0.0_f64;
+        let mut max_drawdown_duration_ms = 0;
+        let mut peak: Option<&EquitySnapshot> = None;
+        for snapshot in curve {
+            match peak {
+                Some(top) if snapshot.equity < top.equity => {
+                    max_drawdown = max_drawdown.max(ratio(top.equity - snapshot.equity, top.equity));
+                    max_drawdown_duration_ms = max_drawdown_duration_ms.max(snapshot.timestamp - top.timestamp);
+                }
+                _ => peak = Some(snapshot),
+            }
+        }
+
+        let trades: Vec<f64> = portfolio.symbols.iter().flat_map(|s| s.closed_trades.iter().copied()).collect();
+        let wins: Vec<f64> = trades.iter().copied().filter(|pnl| *pnl > 0.0).collect();
+        let losses: Vec<f64> = trades.iter().copied().filter(|pnl| *pnl < 0.0).collect();
+        let gross_loss = -losses.iter().sum::<f64>();
+
+        Self {
+            trader_id: trader.id.clone(),
+            total_return,
+            volatility,
+            sharpe_ratio,
+            sortino_ratio,
+            max_drawdown,
+            max_drawdown_duration_ms,
+            closed_trades: trades.len(),
+            hit_rate: ratio(wins.len() as f64, trades.len() as f64),
+            average_win: mean(&wins),
+            average_loss: mean(&losses),
+            profit_factor: (gross_loss > 0.0).then(|| wins.iter().sum::<f64>() / gross_loss),
+            returns,
+        }
+    }
+
+    pub fn display(&self) {
+        print_colored("Performance:", "yellow");
+        print_colored(
+            &format!(
+                "  Return: {:.2}% over {} periods, Volatility: {:.2}%, Sharpe: {:.2}, Sortino: {:.2}",
+                self.total_return * 100.0, self.returns.len(), self.volatility * 100.0, self.sharpe_ratio, self.sortino_ratio
+            ),
+            "cyan"
+        );
+        print_colored(
+            &format!(
+                "  Max Drawdown: {:.2}% lasting {:.1}s",
+                self.max_drawdown * 100.0, self.max_drawdown_duration_ms as f64 / 1000.0
+            ),
+            "cyan"
+        );
+        let profit_factor = self.profit_factor.map(|pf| format!("{:.2}", pf)).unwrap_or_else(|| "n/a".to_string());
+        print_colored(
+            &format!(
+                "  Closed Trades: {}, Hit Rate: {:.1}%, Average Win: ${:.2}, Average Loss: ${:.2}, Profit Factor: {}",
+                self.closed_trades, self.hit_rate * 100.0, self.average_win, self.average_loss, profit_factor
+            ),
+            "cyan"
+        );
+    }
+}
+
+// Rank every trader by return over the session
+pub fn display_leaderboard(reports: &[PerformanceReport]) {
+    let mut ranked: Vec<&PerformanceReport> = reports.iter().collect();
+    ranked.sort_by(|a, b| b.total_return.total_cmp(&a.total_return));
+
+    print_colored("Leaderboard:", "blue");
+    for (rank, report) in ranked.iter().enumerate() {
+        let color = if report.total_return >= 0.0 { "green" } else { "red" };
+        print_colored(
+            &format!(
+                "  {:>2}. {:<12} Return: {:>7.2}%  Sharpe: {:>6.2}  Max Drawdown: {:>6.2}%  Hit Rate: {:>5.1}%",
+                rank + 1, report.trader_id, report.total_return * 100.0, report.sharpe_ratio, report.max_drawdown * 100.0, report.hit_rate * 100.0
+            ),
+            color
+        );
+    }
+}
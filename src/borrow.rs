@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use crate::config::{FeedConfig, LocateConfig};
+use crate::models::OrderRequest;
+use crate::traders::Trader;
+
+// Shares a broker lends its traders for short sales, shared between them, and what it
+// charges for them while they are out. Symbols without a locate cannot be sold short.
+// Fees accrue on model time, the same years per feed tick the prices move over.
+#[derive(Debug, Default)]
+pub struct BorrowDesk {
+    locates: HashMap<String, LocateConfig>,
+    on_loan: HashMap<(String, String), u32>, // Shares each trader has sold or is selling short, by trader and symbol
+    charged_at: HashMap<String, u64>, // When the fee on each symbol was last charged
+    years_per_milli: f64, // Model time that passes with each millisecond of simulated time
+}
+
+impl BorrowDesk {
+    pub fn new(locates: Vec<LocateConfig>, feed: &FeedConfig) -> Self {
+        let locates = locates.into_iter().map(|locate| (locate.symbol.clone(), locate)).collect();
+        let years_per_milli = feed.tick_years / feed.tick_interval_ms as f64;
+        Self { locates, years_per_milli, ..Self::default() }
+    }
+
+    // Find the shares a new sell order sells short. The trader already tracks the order,
+    // so what it sells beyond the shares held is counted in its short shares.
+    pub fn locate(&mut self, request: &OrderRequest, trader: &Trader) -> Result<(), String> {
+        let OrderRequest::New(order) = request else { return Ok(()) };
+        let symbol = &order.stock_symbol;
+        let key = (trader.id.clone(), symbol.clone());
+        let needed = trader.shares_short(symbol);
+        if order.order_type.is_buy() || needed <= self.on_loan.get(&key).copied().unwrap_or(0) {
+            return Ok(());
+        }
+        let Some(locate) = self.locates.get(symbol) else {
+            return Err(format!("No shares of {} to borrow", symbol));
+        };
+        let lent: u32 = self.on_loan.iter()
+            .filter(|((trader_id, lent_symbol), _)| lent_symbol == symbol && *trader_id != trader.id)
+            .map(|(_, shares)| shares)
+            .sum();
+        if lent + needed > locate.shares {
+            return Err(format!("Only {} shares of {} left to borrow", locate.shares.saturating_sub(lent), symbol));
+        }
+        self.on_loan.insert(key, needed);
+        Ok(())
+    }
+
+    // Give back the shares a trader no longer sells short, once its orders closed or it bought them back
+    pub fn refresh(&mut self, trader: &Trader) {
+        for symbol in self.locates.keys() {
+            let key = (trader.id.clone(), symbol.clone());
+            match trader.shares_short(symbol) {
+                0 => self.on_loan.remove(&key),
+                shares => self.on_loan.insert(key, shares),
+            };
+        }
+    }
+
+    // Fee per share sold short of a symbol since it was last charged, at its new price
+    pub fn fee_per_share(&mut self, symbol: &str, price: f64, now: u64) -> Option<f64> {
+        let locate = self.locates.get(symbol)?;
+        let since = self.charged_at.insert(symbol.to_string(), now)?;
+        Some(price * locate.borrow_rate * now.saturating_sub(since) as f64 * self.years_per_milli)
+    }
+}
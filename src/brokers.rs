@@ -1,57 +1,178 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::sync::{broadcast, Barrier, mpsc, Mutex};
-use crate::models::Stock; // Import the Order struct
+use tokio::sync::mpsc::error::TrySendError;
+use crate::models::{OrderRequest, OrderStatusUpdate, RejectCode, Stock};
 // use tokio::time::{sleep, Duration};
 use crate::traders::{run_trader, Trader};
 use crate::color::print_colored; // Import the print_colored function
-use lapin::{Connection, ConnectionProperties, ExchangeKind, BasicProperties, options::*, types::FieldTable};
-use serde::{Deserialize, Serialize};
+use crate::clock::Clock;
+use crate::borrow::BorrowDesk;
+use crate::config::{BrokerConfig, FeedConfig, QueueNames};
+use crate::fees::FeeDesk;
+use crate::margin::MarginDesk;
+use crate::stock_listener::StockStore;
+use crate::risk::{rejection, PreTradeRisk};
+use crate::strategy::Strategy;
+use crate::transport::Transport;
 use futures::StreamExt; // Import the StreamExt trait
 
-#[derive(Serialize, Debug, Deserialize)]
-struct OrderStatusUpdate {
-    order_id: String,
-    status: String,
+// A trader registered with a broker, with the channel its stock updates go through
+struct TraderHandle {
+    stock_tx: mpsc::Sender<Stock>,
+    trader: Arc<Mutex<Trader>>,
 }
 
-pub async fn run_brokers(tx: broadcast::Sender<Stock>, barrier: Arc<Barrier>, traders: Vec<Arc<Mutex<Trader>>>) {
-    for i in 0..5 {
-        let broker_id = format!("B{:03}", i + 1);
+// A trader together with the strategy it trades with
+pub type TraderSetup = (Arc<Mutex<Trader>>, Box<dyn Strategy>);
+
+// Handle to a running broker, used to connect more traders during the session
+#[derive(Clone)]
+pub struct BrokerHandle {
+    pub broker_id: String,
+    join_tx: mpsc::Sender<TraderSetup>,
+}
+
+impl BrokerHandle {
+    pub async fn join(&self, setup: TraderSetup) -> Result<(), String> {
+        self.join_tx.send(setup).await.map_err(|_| format!("Broker {} is no longer running", self.broker_id))
+    }
+}
+
+// Create a channel for a trader and start its trading task
+async fn register_trader(
+    registry: &mut BTreeMap<String, TraderHandle>,
+    (trader, strategy): TraderSetup,
+    order_tx: mpsc::Sender<OrderRequest>,
+) -> String {
+    let trader_id = trader.lock().await.id.clone();
+    let (stock_tx, stock_rx) = mpsc::channel(16);
+    tokio::spawn(run_trader(trader_id.clone(), stock_rx, order_tx, trader.clone(), strategy));
+    registry.insert(trader_id.clone(), TraderHandle { stock_tx, trader });
+    trader_id
+}
+
+// Log an order request from one of the broker's traders and put it on the orders queue
+async fn forward_order(broker_id: &str, transport: &Arc<dyn Transport>, orders_queue: &str, order: &OrderRequest) {
+    let message = match order {
+        OrderRequest::New(order) => format!("{:?} {} shares of {}", order.order_type, order.quantity, order.stock_symbol),
+        OrderRequest::Cancel { order_id, .. } => format!("cancel {}", order_id),
+        OrderRequest::Replace { order_id, quantity, limit_price, .. } => {
+            format!("replace {} with {} shares at ${:.2}", order_id, quantity, limit_price)
+        }
+    };
+    print_colored(&format!("Broker {} received order from Trader {}: {}", broker_id, order.trader_id(), message), "yellow");
+
+    // Serialize the order to JSON
+    let serialized_order = serde_json::to_string(order).unwrap();
+    // Publish the order to the orders queue
+    transport.publish("", orders_queue, serialized_order.as_bytes()).await.unwrap();
+    //println!("Order Sent: {}", serialized_order);
+}
+
+// Where a broker sends its traders' requests, and the checks they pass on the way
+struct OrderGateway {
+    broker_id: String,
+    transport: Arc<dyn Transport>,
+    orders_queue: String,
+    risk: PreTradeRisk,
+    borrow: BorrowDesk,
+    margin: MarginDesk,
+    fees: FeeDesk,
+    clock: Clock,
+}
+
+impl OrderGateway {
+    // Risk check a trader's requests, locate the shares they sell short, check a margin account's
+    // buying power, and forward those that pass. A refusal is settled with the trader straight away,
+    // which may in turn drop or cancel the other orders of its group.
+    async fn send(&mut self, trader: &mut Trader, requests: Vec<OrderRequest>, last_prices: &HashMap<String, f64>, marks: &HashMap<String, Stock>) {
+        let mut queue = VecDeque::from(requests);
+        while let Some(request) = queue.pop_front() {
+            let now = self.clock.now_millis();
+            if self.risk.is_duplicate(&request) {
+                print_colored(&format!("Broker {} refused order from Trader {}: duplicate order id", self.broker_id, trader.id), "red");
+                // Coded so the trader keeps the original order open, which leaves its group alone too
+                if let Some(mut update) = rejection(&request, "Duplicate order id".to_string(), now) {
+                    update.reject_code = Some(RejectCode::DuplicateOrderId);
+                    if let Err(e) = trader.on_status_update(&update) {
+                        println!("Broker {} failed to apply order status update for trader {}: {}", self.broker_id, trader.id, e);
+                    }
+                }
+                continue;
+            }
+            let checked = self.risk.check(&request, trader, last_prices, now)
+                .and_then(|()| self.borrow.locate(&request, trader))
+                .and_then(|()| self.margin.check(&request, trader, marks));
+            let Err(reason) = checked else {
+                forward_order(&self.broker_id, &self.transport, &self.orders_queue, &request).await;
+                continue;
+            };
+            print_colored(&format!("Broker {} refused order from Trader {}: {}", self.broker_id, trader.id, reason), "red");
+            let Some(update) = rejection(&request, reason, now) else { continue };
+            if let Err(e) = trader.on_status_update(&update) {
+                println!("Broker {} failed to apply order status update for trader {}: {}", self.broker_id, trader.id, e);
+            }
+            queue.extend(trader.group_requests(&update.order_id));
+        }
+        self.borrow.refresh(trader);
+    }
+
+    // Liquidations and the cancels ahead of them only ever reduce risk, they skip the checks
+    async fn force(&self, requests: Vec<OrderRequest>) {
+        for request in requests {
+            forward_order(&self.broker_id, &self.transport, &self.orders_queue, &request).await;
+        }
+    }
+}
+
+// Copy of the latest prices, taken before any trader is locked so the store is never held
+// while waiting on a trader
+async fn marks(stock_store: &StockStore) -> HashMap<String, Stock> {
+    stock_store.read().await.clone()
+}
+
+// Each broker comes with the traders it serves, more can join through the returned handles
+#[allow(clippy::too_many_arguments)]
+pub async fn run_brokers(
+    transport: Arc<dyn Transport>,
+    queues: QueueNames,
+    tx: broadcast::Sender<Stock>,
+    barrier: Arc<Barrier>,
+    clock: Clock,
+    stock_store: StockStore,
+    feed: &FeedConfig,
+    brokers: Vec<(BrokerConfig, Vec<TraderSetup>)>,
+) -> Vec<BrokerHandle> {
+    let mut handles = Vec::new();
+    for (broker, traders) in brokers {
+        let broker_id = broker.id;
         let mut stock_rx = tx.subscribe(); // Subscribe each broker to the broadcast channel
         let barrier_clone = barrier.clone();
 
-        // Create channels for the broker to communicate with its traders
-        let (trader_tx1, trader_rx1) = mpsc::channel(16);
-        let (trader_tx2, trader_rx2) = mpsc::channel(16);
-        let (trader_tx3, trader_rx3) = mpsc::channel(16);
-
         // Create a channel for orders from traders to the broker
         let (order_tx, mut order_rx) = mpsc::channel(16);
 
-        // Assign traders to this broker
-        let trader1 = traders[i * 3].clone();
-        let trader2 = traders[i * 3 + 1].clone();
-        let trader3 = traders[i * 3 + 2].clone();
+        // Create a channel for traders joining mid-session
+        let (join_tx, mut join_rx) = mpsc::channel(16);
+        handles.push(BrokerHandle { broker_id: broker_id.clone(), join_tx });
 
         // Maintain a HashMap of stock symbols to their latest prices
         let mut stock_prices: HashMap<String, f64> = HashMap::new();
 
-        // Establish connection to RabbitMQ server for sending orders
-        let conn = Connection::connect("amqp://127.0.0.1:5672/%2f", ConnectionProperties::default()).await.unwrap();
-        let channel = conn.create_channel().await.unwrap();
-        channel.exchange_declare(
-            "orders",
-            ExchangeKind::Direct,
-            ExchangeDeclareOptions::default(),
-            FieldTable::default(),
-        ).await.unwrap();
-
-        // Establish connection to RabbitMQ server for receiving order status updates
-        let status_conn = Connection::connect("amqp://127.0.0.1:5672/%2f", ConnectionProperties::default()).await.unwrap();
-        let status_channel = status_conn.create_channel().await.unwrap();
-        let _status_queue = status_channel.queue_declare("processed_order_status", QueueDeclareOptions::default(), FieldTable::default()).await.unwrap();
-        let mut status_consumer = status_channel.basic_consume("processed_order_status", "", BasicConsumeOptions::default(), FieldTable::default()).await.unwrap();
+        // Every broker sees every order status update and keeps those for its own traders
+        let mut status_consumer = transport.subscribe(&queues.processed_order_status, "").await.unwrap();
+        let stock_store = stock_store.clone();
+        let mut gateway = OrderGateway {
+            broker_id: broker_id.clone(),
+            transport: transport.clone(),
+            orders_queue: queues.orders.clone(),
+            risk: PreTradeRisk::new(broker.risk),
+            borrow: BorrowDesk::new(broker.locates, feed),
+            margin: MarginDesk::new(broker.margin),
+            fees: FeeDesk::new(broker.fees),
+            clock,
+        };
 
         // Spawn the broker task
         tokio::spawn(async move {
@@ -60,14 +181,19 @@ pub async fn run_brokers(tx: broadcast::Sender<Stock>, barrier: Arc<Barrier>, tr
             // Wait at the barrier
             barrier_clone.wait().await;
 
-            // Spawn three traders for each broker
-            tokio::spawn(run_trader(format!("{}-T001", broker_id), trader_rx1, order_tx.clone(), trader1.clone()));
-            tokio::spawn(run_trader(format!("{}-T002", broker_id), trader_rx2, order_tx.clone(), trader2.clone()));
-            tokio::spawn(run_trader(format!("{}-T003", broker_id), trader_rx3, order_tx.clone(), trader3.clone()));
+            // Registry of the traders served by this broker, keyed by trader id and ordered so updates fan out the same way every run
+            let mut registry: BTreeMap<String, TraderHandle> = BTreeMap::new();
+            for trader in traders {
+                register_trader(&mut registry, trader, order_tx.clone()).await;
+            }
 
+            // Order status keeps flowing after the feed closes, so orders expiring at the close still settle
+            let mut feed_open = true;
             loop {
+                // Poll the branches in a fixed order so seeded runs make the same choices
                 tokio::select! {
-                    stock = stock_rx.recv() => {
+                    biased;
+                    stock = stock_rx.recv(), if feed_open => {
                         match stock {
                             Ok(stock) => {
                                 // print_colored(&format!(
@@ -78,15 +204,27 @@ pub async fn run_brokers(tx: broadcast::Sender<Stock>, barrier: Arc<Barrier>, tr
                                 // Update the latest stock price
                                 stock_prices.insert(stock.symbol.clone(), stock.price);
 
-                                // Forward the stock update to traders
-                                if let Err(e) = trader_tx1.send(stock.clone()).await {
-                                    print_colored(&format!("Broker {} failed to send stock update to trader 1: {:?}", broker_id, e), "red");
-                                }
-                                if let Err(e) = trader_tx2.send(stock.clone()).await {
-                                    print_colored(&format!("Broker {} failed to send stock update to trader 2: {:?}", broker_id, e), "red");
+                                // Charge the traders short the symbol for the time since its last price,
+                                // then check the margin accounts against the new price
+                                let now = clock.now_millis();
+                                let fee = gateway.borrow.fee_per_share(&stock.symbol, stock.price, now);
+                                let marks = marks(&stock_store).await;
+                                for handle in registry.values() {
+                                    let mut trader = handle.trader.lock().await;
+                                    let shorted = u32::try_from(-trader.position(&stock.symbol)).unwrap_or(0);
+                                    if let Some(fee) = fee.filter(|_| shorted > 0) {
+                                        trader.pay_borrow_fee(fee * shorted as f64);
+                                    }
+                                    let liquidation = gateway.margin.monitor(&mut trader, &marks, now);
+                                    gateway.force(liquidation).await;
                                 }
-                                if let Err(e) = trader_tx3.send(stock.clone()).await {
-                                    print_colored(&format!("Broker {} failed to send stock update to trader 3: {:?}", broker_id, e), "red");
+
+                                // Forward the stock update to traders. A trader still busy with earlier ticks misses
+                                // this one rather than holding up the broker, whose orders it may be waiting on.
+                                for (trader_id, handle) in &registry {
+                                    if let Err(TrySendError::Closed(_)) = handle.stock_tx.try_send(stock.clone()) {
+                                        print_colored(&format!("Broker {} failed to send stock update to trader {}: channel closed", broker_id, trader_id), "red");
+                                    }
                                 }
 
                                 // Simulate broadcasting to traders
@@ -99,29 +237,26 @@ pub async fn run_brokers(tx: broadcast::Sender<Stock>, barrier: Arc<Barrier>, tr
                             }
                             Err(tokio::sync::broadcast::error::RecvError::Closed) => {
                                 print_colored(&format!("Broker {} channel closed", broker_id), "red");
-                                break;
+                                feed_open = false;
                             }
                         }
                     }
+                    trader = join_rx.recv() => {
+                        if let Some(trader) = trader {
+                            let trader_id = register_trader(&mut registry, trader, order_tx.clone()).await;
+                            print_colored(&format!("Trader {} joined broker {}.", trader_id, broker_id), "cyan");
+                        }
+                    }
                     order = order_rx.recv() => {
                         match order {
                             Some(order) => {
-                                print_colored(&format!(
-                                    "Broker {} received order from Trader {}: {:?} {} shares of {}",
-                                    broker_id, order.trader_id, order.order_type, order.quantity, order.stock_symbol
-                                ), "yellow");
-
-                                // Serialize the order to JSON
-                                let serialized_order = serde_json::to_string(&order).unwrap();
-                                // Publish the order to the "orders" queue
-                                channel.basic_publish(
-                                    "",
-                                    "orders",
-                                    BasicPublishOptions::default(),
-                                    serialized_order.as_bytes(),
-                                    BasicProperties::default(),
-                                ).await.unwrap();
-                                //println!("Order Sent: {}", serialized_order);
+                                let Some(handle) = registry.get(order.trader_id()) else {
+                                    print_colored(&format!("Broker {} dropped order from unknown trader {}", broker_id, order.trader_id()), "red");
+                                    continue;
+                                };
+                                let marks = marks(&stock_store).await;
+                                let mut trader = handle.trader.lock().await;
+                                gateway.send(&mut trader, vec![order], &stock_prices, &marks).await;
                             }
                             None => {
                                 print_colored(&format!("Broker {} order channel closed", broker_id), "red");
@@ -136,7 +271,7 @@ pub async fn run_brokers(tx: broadcast::Sender<Stock>, barrier: Arc<Barrier>, tr
                                 //println!("Broker {} received order status update: {}", broker_id, status_data);
 
                                 // Deserialize the JSON to order status update data
-                                let status_update: OrderStatusUpdate = match serde_json::from_str(&status_data) {
+                                let mut status_update: OrderStatusUpdate = match serde_json::from_str(&status_data) {
                                     Ok(status_update) => status_update,
                                     Err(err) => {
                                         println!("Broker {} failed to deserialize order status update: {}", broker_id, err);
@@ -148,43 +283,35 @@ pub async fn run_brokers(tx: broadcast::Sender<Stock>, barrier: Arc<Barrier>, tr
                                 //println!("Broker {} processing order status update: {:?}", broker_id, status_update);
 
 
-                                // Update the trader's held stock based on the order status
-                                // This is a placeholder for the actual logic to update the trader's portfolio
-                                // You need to implement the logic to find the trader and update their portfolio
-                                // based on the order status update
-                                // Find the trader who made the order
-                                let trader_id = &status_update.order_id[5..9];
-                                let trader = match trader_id {
-                                    "T001" => trader1.clone(),
-                                    "T002" => trader2.clone(),
-                                    "T003" => trader3.clone(),
-                                    _ => {
-                                        println!("Broker {} could not find trader with id: {}", broker_id, trader_id);
-                                        continue;
-                                    }
+                                // Find the trader who made the order, reports for other brokers' traders are skipped
+                                let Some(handle) = registry.get(&status_update.trader_id) else {
+                                    delivery.ack().await.unwrap();
+                                    continue;
                                 };
 
                                 // Introduce a small delay to ensure the pending order is added
                                 //sleep(Duration::from_millis(100)).await;
                                 // Complete the order for the trader
-                                let mut trader = trader.lock().await;
+                                let marks = marks(&stock_store).await;
+                                let mut trader = handle.trader.lock().await;
+                                gateway.fees.charge(&mut status_update, &trader);
                                 //println!("Pending orders for trader {}: {:?}", trader_id, trader.pending_orders); // Debugging information
                                 //println!("Looking for order ID: {}", status_update.order_id); // Debugging information
-                                if let Some(pos) = trader.pending_orders.iter().position(|o| o.order_id == status_update.order_id) {
-                                    let order = trader.pending_orders.remove(pos);
-                                    if let Err(e) = trader.complete_order(&order, stock_prices.get(&order.stock_symbol).cloned().unwrap_or(0.0)) {
-                                        println!("Broker {} failed to complete order for trader {}: {}", broker_id, trader_id, e);
-                                    } else {
-                                        //println!("Broker {} successfully completed order for trader {}: {:?}", broker_id, trader_id, order);
+                                // Fills settle at the price they traded less the broker's fees, repeated or out of order reports are refused by the trader's order tracker
+                                if let Err(e) = trader.on_status_update(&status_update) {
+                                    println!("Broker {} failed to apply order status update for trader {}: {}", broker_id, trader.id, e);
+                                }
+                                // Send the exits of a filled bracket entry, or cancel the other legs of one that traded
+                                let follow_ups = trader.group_requests(&status_update.order_id);
+                                gateway.send(&mut trader, follow_ups, &stock_prices, &marks).await;
+                                if cfg!(debug_assertions) {
+                                    if let Err(e) = trader.reconcile() {
+                                        print_colored(&format!("Broker {} books out of balance: {}", broker_id, e), "red");
                                     }
-                                } else {
-                                    // print_colored(&format!("Broker {} could not find pending order from trader id: {} (trader revert the order) ", broker_id, status_update.order_id), "yellow");
-                                    // Clean the pending order with that order ID
-                                    trader.remove_pending_order(&status_update.order_id);
                                 }
 
                                 // Acknowledge the message
-                                delivery.ack(BasicAckOptions::default()).await.unwrap();
+                                delivery.ack().await.unwrap();
 
                                 // Print a success message
                                 //print_colored(&format!("Broker {} successfully processed order status update for order_id: {}", broker_id, status_update.order_id), "green");
@@ -202,4 +329,5 @@ pub async fn run_brokers(tx: broadcast::Sender<Stock>, barrier: Arc<Barrier>, tr
             }
         });
     }
-}
\ No newline at end of file
+    handles
+}
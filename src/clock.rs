@@ -0,0 +1,46 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::Instant;
+use crate::config::ClockMode;
+
+// Simulated market time shared by the feed, the matching engine and the session controller.
+// Built on tokio's clock: in as-fast-as-possible mode the runtime starts with time paused and
+// tokio jumps straight to the next timer whenever every task is idle, which gives discrete-event
+// simulation without changing any of the components.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    origin: Instant,
+    start_millis: u64,
+    speed: f64, // Simulated seconds per tokio second
+}
+
+impl Clock {
+    // Must be created inside the runtime returned by `build_runtime`
+    pub fn new(mode: ClockMode) -> Self {
+        let start_millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let speed = match mode {
+            ClockMode::Accelerated { speed } => speed,
+            ClockMode::RealTime | ClockMode::AsFastAsPossible => 1.0,
+        };
+        Self { origin: Instant::now(), start_millis, speed }
+    }
+
+    // Current simulated time in milliseconds since the Unix epoch
+    pub fn now_millis(&self) -> u64 {
+        self.start_millis + (self.origin.elapsed().as_secs_f64() * self.speed * 1000.0) as u64
+    }
+
+    // Wait for a span of simulated time
+    pub async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration.div_f64(self.speed)).await;
+    }
+}
+
+// As-fast-as-possible and seeded runs use a single threaded runtime so tasks interleave the same way every time
+pub fn build_runtime(mode: ClockMode, deterministic: bool) -> std::io::Result<Runtime> {
+    match mode {
+        ClockMode::AsFastAsPossible => Builder::new_current_thread().enable_all().start_paused(true).build(),
+        _ if deterministic => Builder::new_current_thread().enable_all().build(),
+        _ => Builder::new_multi_thread().enable_all().build(),
+    }
+}
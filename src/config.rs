@@ -0,0 +1,508 @@
+use std::collections::HashSet;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+// Market scenario loaded at startup, every field falls back to the built-in default market
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MarketConfig {
+    pub transport: TransportKind,
+    pub amqp_url: String,
+    pub queues: QueueNames,
+    pub symbols: Vec<SymbolConfig>,
+    pub feed: FeedConfig,
+    pub session_secs: u64,
+    pub seed: Option<u64>, // Makes every run with the same seed draw the same random numbers
+    pub clock: ClockMode,
+    pub brokers: Vec<BrokerConfig>,
+    pub cost_basis: CostBasis, // How the session report matches sells against earlier buys
+    pub snapshot_interval_ms: u64, // Simulated time between two equity snapshots of every trader
+    pub report_dir: Option<String>, // Where the JSON and CSV session reports are written at market close
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportKind {
+    Amqp,
+    InMemory,
+}
+
+// Lot matching used to split realized from unrealized profit
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CostBasis {
+    Fifo,
+    #[default]
+    AverageCost,
+}
+
+// Exchange and queue names used on the message transport
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QueueNames {
+    pub stocks: String,
+    pub orders: String,
+    pub order_status: String,
+    pub processed_order_status: String,
+    pub depth: String, // Exchange the matching engine publishes the visible book on
+}
+
+// A symbol is either just its name or a full entry with its own starting price and model
+#[derive(Deserialize, Debug, Clone)]
+#[serde(from = "SymbolEntry")]
+pub struct SymbolConfig {
+    pub symbol: String,
+    pub start_price: f64,
+    pub model: Option<PriceModelConfig>, // Falls back to the feed's default model
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SymbolEntry {
+    Name(String),
+    Detailed {
+        symbol: String,
+        #[serde(default = "default_start_price")]
+        start_price: f64,
+        #[serde(default)]
+        model: Option<PriceModelConfig>,
+    },
+}
+
+impl From<SymbolEntry> for SymbolConfig {
+    fn from(entry: SymbolEntry) -> Self {
+        match entry {
+            SymbolEntry::Name(symbol) => Self { symbol, start_price: default_start_price(), model: None },
+            SymbolEntry::Detailed { symbol, start_price, model } => Self { symbol, start_price, model },
+        }
+    }
+}
+
+// Stochastic process driving a symbol's price, rates are annualised
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PriceModelConfig {
+    Gbm {
+        drift: f64,
+        volatility: f64,
+    },
+    JumpDiffusion {
+        drift: f64,
+        volatility: f64,
+        jump_intensity: f64,
+        jump_mean: f64,
+        jump_volatility: f64,
+    },
+    MeanReversion {
+        speed: f64,
+        #[serde(default)]
+        mean: Option<f64>, // Defaults to the starting price
+        volatility: f64,
+    },
+}
+
+impl PriceModelConfig {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            PriceModelConfig::Gbm { volatility, .. } | PriceModelConfig::MeanReversion { volatility, .. } if *volatility < 0.0 => {
+                Err("Price model volatility cannot be negative".to_string())
+            }
+            PriceModelConfig::JumpDiffusion { volatility, jump_intensity, jump_volatility, .. }
+                if *volatility < 0.0 || *jump_intensity < 0.0 || *jump_volatility < 0.0 =>
+            {
+                Err("Jump diffusion volatilities and jump intensity cannot be negative".to_string())
+            }
+            PriceModelConfig::MeanReversion { speed, .. } if *speed < 0.0 => {
+                Err("Mean reversion speed cannot be negative".to_string())
+            }
+            PriceModelConfig::MeanReversion { mean: Some(mean), .. } if *mean <= 0.0 => {
+                Err("Mean reversion needs a positive mean".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// How fast simulated time runs compared to the wall clock
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ClockMode {
+    RealTime,
+    Accelerated { speed: f64 },
+    AsFastAsPossible, // Discrete-event mode, only supported with the in-memory transport
+}
+
+// Where stock prices come from
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FeedSource {
+    // Simulate every configured symbol with its price model
+    Model,
+    // Replay CSV files of ticks (symbol,timestamp,price,volume) or bars (symbol,timestamp,open,high,low,close,volume),
+    // with timestamps in milliseconds since the Unix epoch and an optional header row
+    Replay { files: Vec<String> },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FeedConfig {
+    pub source: FeedSource,
+    pub tick_interval_ms: u64, // Time between two price updates
+    pub tick_years: f64, // Model time covered by one price update, one trading day by default
+    pub default_model: PriceModelConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BrokerConfig {
+    pub id: String,
+    pub traders: Vec<TraderConfig>,
+    #[serde(default)]
+    pub risk: RiskLimits,
+    #[serde(default)]
+    pub locates: Vec<LocateConfig>, // Symbols the broker can lend its traders for short sales
+    #[serde(default)]
+    pub margin: MarginConfig, // Terms of its traders' margin accounts
+    #[serde(default)]
+    pub fees: FeeSchedule, // What its traders pay on every fill
+}
+
+// Commission a broker charges on each fill, plus the exchange's charge for taking liquidity or
+// rebate for providing it, which the broker passes on. A negative maker fee is a rebate.
+// An order is charged at least the minimum ticket over its fills, rebates aside.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FeeSchedule {
+    pub per_share: f64,
+    pub notional_bps: f64, // Basis points of the traded value, 1 is 0.01%
+    pub minimum: f64,
+    pub maker_per_share: f64, // Fills of orders that were resting in the book
+    pub taker_per_share: f64, // Fills of orders that traded on arrival
+}
+
+// Equity a margin account must keep, as fractions of the value of its positions long or short.
+// The initial margin gates new orders, falling below the maintenance margin brings a margin call.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MarginConfig {
+    pub initial: f64,
+    pub maintenance: f64,
+    pub grace_period_secs: u64, // How long a margin call has to be met before positions are liquidated
+    pub symbols: Vec<SymbolMarginConfig>, // Symbols with their own rates
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SymbolMarginConfig {
+    pub symbol: String,
+    pub initial: f64,
+    pub maintenance: f64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LocateConfig {
+    pub symbol: String,
+    pub shares: u32, // Lendable across all of the broker's traders at once
+    pub borrow_rate: f64, // Yearly fee on the value of the shares on loan, 0.05 is 5%
+}
+
+// Pre-trade limits a broker holds its traders' orders to, each one is off unless set
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RiskLimits {
+    pub max_order_quantity: Option<u32>,
+    pub max_order_notional: Option<f64>,
+    pub max_position: Option<u32>, // Shares of one symbol held long or short, counting what open orders would add
+    pub max_gross_exposure: Option<f64>, // Value of every holding long or short plus every open buy
+    pub price_collar_pct: Option<f64>, // Furthest a limit price may stray from the last price
+    pub max_orders_per_sec: Option<u32>, // New orders and replaces per trader per simulated second
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TraderConfig {
+    pub id: String,
+    #[serde(default = "default_initial_cash")]
+    pub initial_cash: f64,
+    #[serde(default)]
+    pub holdings: Vec<HoldingConfig>,
+    #[serde(default)]
+    pub join_after_secs: Option<u64>, // Connect to the broker this long after the market opens
+    #[serde(default)]
+    pub short_selling: bool, // May sell shares it does not hold, borrowed through its broker
+    #[serde(default)]
+    pub margin_account: bool, // May borrow from its broker to buy, against the equity it holds
+    #[serde(default)]
+    pub strategy: StrategyConfig,
+}
+
+// Trading logic a trader runs
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StrategyConfig {
+    #[default]
+    Random,
+    MovingAverageCrossover {
+        fast_window: usize,
+        slow_window: usize,
+        quantity: u32,
+        #[serde(default)]
+        exits: Option<ExitConfig>, // Enter with bracket orders and keep held shares covered by exits
+    },
+    BollingerBands {
+        window: usize,
+        num_std_dev: f64,
+        quantity: u32,
+        #[serde(default)]
+        trailing_stop_pct: Option<f64>, // Protect each position with a trailing stop this far below the high
+    },
+    MarketMaker {
+        symbols: Vec<String>,
+        half_spread_bps: f64,
+        quantity: u32,
+        max_inventory: u32,
+        #[serde(default)]
+        inventory_skew: f64,
+        #[serde(default)]
+        display_quantity: Option<u32>, // Quote as icebergs showing only this many shares
+    },
+    Pairs {
+        first: String,
+        second: String,
+        window: usize,
+        entry_z: f64,
+        exit_z: f64,
+        quantity: u32,
+    },
+}
+
+// Take-profit and stop-loss distances from the entry price, in percent
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct ExitConfig {
+    pub take_profit_pct: f64,
+    pub stop_loss_pct: f64,
+}
+
+impl StrategyConfig {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            StrategyConfig::MovingAverageCrossover { fast_window, slow_window, .. } if *fast_window == 0 || fast_window >= slow_window => {
+                Err("Moving average crossover needs 0 < fast_window < slow_window".to_string())
+            }
+            StrategyConfig::MovingAverageCrossover { exits: Some(exits), .. }
+                if exits.take_profit_pct <= 0.0 || exits.stop_loss_pct <= 0.0 || exits.stop_loss_pct >= 100.0 =>
+            {
+                Err("Moving average crossover exits need a positive take profit and a stop loss between 0% and 100%".to_string())
+            }
+            StrategyConfig::BollingerBands { window, .. } if *window < 2 => {
+                Err("Bollinger bands need a window of at least 2".to_string())
+            }
+            StrategyConfig::BollingerBands { trailing_stop_pct: Some(percent), .. } if *percent <= 0.0 || *percent >= 100.0 => {
+                Err("Bollinger bands need a trailing stop between 0% and 100%".to_string())
+            }
+            StrategyConfig::MarketMaker { display_quantity: Some(0), .. } => {
+                Err("Market maker display quantity must be positive".to_string())
+            }
+            StrategyConfig::Pairs { first, second, window, entry_z, exit_z, .. } => {
+                if first == second {
+                    Err("Pairs trading needs two different symbols".to_string())
+                } else if *window < 3 {
+                    Err("Pairs trading needs a window of at least 3".to_string())
+                } else if exit_z >= entry_z {
+                    Err("Pairs trading needs exit_z below entry_z".to_string())
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HoldingConfig {
+    pub symbol: String,
+    pub quantity: u32,
+    pub average_cost: f64,
+}
+
+fn default_initial_cash() -> f64 {
+    5000.0
+}
+
+fn default_start_price() -> f64 {
+    100.0
+}
+
+// Regulation T rates
+impl Default for MarginConfig {
+    fn default() -> Self {
+        Self { initial: 0.5, maintenance: 0.25, grace_period_secs: 5, symbols: Vec::new() }
+    }
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        Self {
+            source: FeedSource::Model,
+            tick_interval_ms: 1000,
+            tick_years: 1.0 / 252.0,
+            default_model: PriceModelConfig::Gbm { drift: 0.05, volatility: 0.3 },
+        }
+    }
+}
+
+impl Default for QueueNames {
+    fn default() -> Self {
+        Self {
+            stocks: "stocks".to_string(),
+            orders: "orders".to_string(),
+            order_status: "order_status".to_string(),
+            processed_order_status: "processed_order_status".to_string(),
+            depth: "depth".to_string(),
+        }
+    }
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        let symbols = [
+            "AAPL", "GOOGL", "AMZN", "MSFT", "TSLA", "FB", "NFLX", "NVDA", "BABA", "V",
+            "JPM", "JNJ", "WMT", "PG", "MA", "DIS", "HD", "PYPL", "BAC", "VZ",
+            "ADBE", "CMCSA", "PFE", "KO", "PEP", "INTC", "CSCO", "MRK", "XOM", "NKE",
+            "T", "ABT", "CVX", "LLY", "MCD", "MDT", "UNH", "WFC", "BMY", "COST",
+            "NEE", "PM", "HON", "IBM", "TXN", "LIN", "UNP", "QCOM", "LOW", "ORCL",
+            "SBUX", "RTX", "CAT", "GS", "MS", "BLK", "AMGN", "SPGI", "PLD", "TMO"
+        ];
+
+        // 5 brokers with 3 traders each
+        let brokers = (1..=5).map(|b| {
+            let id = format!("B{:03}", b);
+            let traders = (1..=3).map(|t| TraderConfig {
+                id: format!("{}-T{:03}", id, t),
+                initial_cash: default_initial_cash(),
+                holdings: Vec::new(),
+                join_after_secs: None,
+                short_selling: false,
+                margin_account: false,
+                strategy: StrategyConfig::Random,
+            }).collect();
+            BrokerConfig { id, traders, risk: RiskLimits::default(), locates: Vec::new(), margin: MarginConfig::default(), fees: FeeSchedule::default() }
+        }).collect();
+
+        Self {
+            transport: TransportKind::Amqp,
+            amqp_url: "amqp://127.0.0.1:5672/%2f".to_string(),
+            queues: QueueNames::default(),
+            symbols: symbols.iter().map(|s| SymbolEntry::Name(s.to_string()).into()).collect(),
+            feed: FeedConfig::default(),
+            session_secs: 60,
+            seed: None,
+            cost_basis: CostBasis::default(),
+            snapshot_interval_ms: 1000,
+            report_dir: None,
+            clock: ClockMode::RealTime,
+            brokers,
+        }
+    }
+}
+
+impl MarketConfig {
+    // Load the scenario from a JSON file, or use the default market when the file does not exist.
+    // Call `validate` once any command line overrides have been applied.
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let config = if Path::new(path).exists() {
+            let contents = std::fs::read_to_string(path)?;
+            serde_json::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path, e))?
+        } else {
+            println!("Config {} not found, using the default market", path);
+            MarketConfig::default()
+        };
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.brokers.is_empty() {
+            return Err("Config must declare at least one broker".to_string());
+        }
+        match self.clock {
+            ClockMode::Accelerated { speed } if speed <= 0.0 => {
+                return Err("Accelerated clock speed must be positive".to_string());
+            }
+            // Waiting on RabbitMQ looks idle to the paused runtime and would make time jump
+            ClockMode::AsFastAsPossible if self.transport != TransportKind::InMemory => {
+                return Err("The as_fast_as_possible clock requires the in_memory transport".to_string());
+            }
+            _ => {}
+        }
+        if self.symbols.is_empty() {
+            return Err("Config must declare at least one symbol".to_string());
+        }
+        if self.snapshot_interval_ms == 0 {
+            return Err("Equity snapshot interval must be positive".to_string());
+        }
+        if let FeedSource::Replay { files } = &self.feed.source {
+            if files.is_empty() {
+                return Err("Replay feed needs at least one file".to_string());
+            }
+        }
+        // A zero interval would spin the feed without simulated time ever moving on
+        if self.feed.tick_interval_ms == 0 {
+            return Err("Feed tick interval must be positive".to_string());
+        }
+        if self.feed.tick_years <= 0.0 {
+            return Err("Feed tick_years must be positive".to_string());
+        }
+        self.feed.default_model.validate()?;
+        for symbol in &self.symbols {
+            if symbol.start_price <= 0.0 {
+                return Err(format!("Symbol {} must start at a positive price", symbol.symbol));
+            }
+            if let Some(model) = &symbol.model {
+                model.validate().map_err(|e| format!("Symbol {}: {}", symbol.symbol, e))?;
+            }
+        }
+
+        let mut ids = HashSet::new();
+        for broker in &self.brokers {
+            if !ids.insert(broker.id.clone()) {
+                return Err(format!("Duplicate broker id {}", broker.id));
+            }
+            let risk = &broker.risk;
+            if risk.max_order_quantity == Some(0) || risk.max_position == Some(0) || risk.max_orders_per_sec == Some(0) {
+                return Err(format!("Broker {} risk limits must be positive", broker.id));
+            }
+            if [risk.max_order_notional, risk.max_gross_exposure, risk.price_collar_pct].iter().flatten().any(|limit| *limit <= 0.0) {
+                return Err(format!("Broker {} risk limits must be positive", broker.id));
+            }
+            let mut located = HashSet::new();
+            for locate in &broker.locates {
+                if !located.insert(locate.symbol.as_str()) {
+                    return Err(format!("Broker {} lists locates for {} twice", broker.id, locate.symbol));
+                }
+                if locate.borrow_rate < 0.0 {
+                    return Err(format!("Broker {} borrow rate for {} cannot be negative", broker.id, locate.symbol));
+                }
+            }
+            let margin = &broker.margin;
+            let rates = [(margin.initial, margin.maintenance)].into_iter()
+                .chain(margin.symbols.iter().map(|symbol| (symbol.initial, symbol.maintenance)));
+            for (initial, maintenance) in rates {
+                if maintenance <= 0.0 || maintenance > initial || initial > 1.0 {
+                    return Err(format!("Broker {} margin rates need 0 < maintenance <= initial <= 1", broker.id));
+                }
+            }
+            let fees = &broker.fees;
+            if [fees.per_share, fees.notional_bps, fees.minimum, fees.taker_per_share].iter().any(|fee| *fee < 0.0) {
+                return Err(format!("Broker {} fees cannot be negative, only the maker fee can be a rebate", broker.id));
+            }
+            for trader in &broker.traders {
+                if !ids.insert(trader.id.clone()) {
+                    return Err(format!("Duplicate trader id {}", trader.id));
+                }
+                if trader.initial_cash < 0.0 {
+                    return Err(format!("Trader {} cannot start with negative cash", trader.id));
+                }
+                trader.strategy.validate().map_err(|e| format!("Trader {}: {}", trader.id, e))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use crate::config::FeeSchedule;
+use crate::models::{Liquidity, OrderStatus, OrderStatusUpdate};
+use crate::traders::Trader;
+
+impl FeeSchedule {
+    // Commission on `quantity` shares traded at `price`, before the minimum ticket
+    fn commission(&self, quantity: u32, price: f64) -> f64 {
+        let shares = quantity as f64;
+        shares * self.per_share + shares * price * self.notional_bps / 10_000.0
+    }
+
+    // Most an order for `quantity` shares at `price` can be charged over all its fills
+    pub fn most_for(&self, quantity: u32, price: f64) -> f64 {
+        let exchange = self.maker_per_share.max(self.taker_per_share).max(0.0);
+        self.commission(quantity, price).max(self.minimum) + exchange * quantity as f64
+    }
+}
+
+// Works out what a broker's traders pay on each fill under its fee schedule. The commission on
+// an order's first fill is raised to the minimum ticket, later fills only pay their own share.
+#[derive(Debug, Default)]
+pub struct FeeDesk {
+    schedule: FeeSchedule,
+    commissions: HashMap<String, f64>, // Commission charged so far on each open order
+}
+
+impl FeeDesk {
+    pub fn new(schedule: FeeSchedule) -> Self {
+        Self { schedule, ..Self::default() }
+    }
+
+    // Put the fees for a fill on its report, the trader pays them when it settles the fill.
+    // Only a fill the trader has not booked yet is charged, repeated reports pass through as they are.
+    pub fn charge(&mut self, update: &mut OrderStatusUpdate, trader: &Trader) {
+        if let (Some(price), true) = (update.fill_price, trader.orders.is_new_fill(update)) {
+            let shares = update.fill_quantity as f64;
+            let charged = self.commissions.entry(update.order_id.clone()).or_default();
+            let commission = self.schedule.commission(update.fill_quantity, price).max(self.schedule.minimum - *charged);
+            *charged += commission;
+            let exchange = match update.liquidity {
+                Some(Liquidity::Maker) => self.schedule.maker_per_share,
+                Some(Liquidity::Taker) => self.schedule.taker_per_share,
+                None => 0.0,
+            };
+            update.fees = commission + exchange * shares;
+        }
+        if matches!(update.status, OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Rejected | OrderStatus::Expired) {
+            self.commissions.remove(&update.order_id);
+        }
+    }
+}
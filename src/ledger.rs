@@ -0,0 +1,141 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use crate::config::CostBasis;
+use crate::traders::OwnedPosition;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+// One execution against a trader's order, the ledger only ever grows
+#[derive(Debug, Clone, Serialize)]
+pub struct Fill {
+    pub timestamp: u64, // Simulated milliseconds since the Unix epoch
+    pub order_id: String,
+    pub symbol: String,
+    pub side: Side,
+    pub quantity: u32,
+    pub price: f64,
+    pub fees: f64,
+}
+
+// Where a symbol's profit came from over the session
+#[derive(Debug, Clone, Serialize)]
+pub struct SymbolPnl {
+    pub symbol: String,
+    pub quantity: i64, // Negative when short
+    pub cost_basis: f64, // Cost of the shares still held under the chosen matching, negative proceeds when short
+    pub latest_price: f64,
+    pub realized_pnl: f64,
+    pub unrealized_pnl: f64,
+    pub fees: f64,
+    pub turnover: f64, // Traded value, buys and sells
+    pub round_trips: u32, // Times the position was traded back to flat
+    pub closed_trades: Vec<f64>, // Profit of each fill that closed shares, after its fees
+}
+
+// Open lots of one symbol as (quantity, price), oldest first. Every lot is on the same side,
+// negative quantities for shares sold short. Average cost keeps a single merged lot.
+#[derive(Debug, Default)]
+struct Lots {
+    lots: VecDeque<(i64, f64)>,
+}
+
+impl Lots {
+    fn quantity(&self) -> i64 {
+        self.lots.iter().map(|(quantity, _)| quantity).sum()
+    }
+
+    fn cost(&self) -> f64 {
+        self.lots.iter().map(|(quantity, price)| *quantity as f64 * price).sum()
+    }
+
+    // Close the oldest lots on the other side first, what is left opens a lot.
+    // Returns the realized profit and whether any shares were closed.
+    fn trade(&mut self, mut quantity: i64, price: f64, method: CostBasis) -> (f64, bool) {
+        let mut realized = 0.0;
+        let mut closed = false;
+        while quantity != 0 {
+            let Some((held, cost)) = self.lots.front_mut().filter(|(held, _)| held.signum() == -quantity.signum()) else { break };
+            let matched = quantity.abs().min(held.abs()) * held.signum();
+            realized += (price - *cost) * matched as f64;
+            closed = true;
+            *held -= matched;
+            quantity += matched;
+            if *held == 0 {
+                self.lots.pop_front();
+            }
+        }
+        if quantity != 0 {
+            match (method, self.lots.front_mut()) {
+                (CostBasis::AverageCost, Some((held, average))) => {
+                    let total = *held + quantity;
+                    *average = (*average * *held as f64 + price * quantity as f64) / total as f64;
+                    *held = total;
+                }
+                _ => self.lots.push_back((quantity, price)),
+            }
+        }
+        (realized, closed)
+    }
+}
+
+// Replay the starting holdings and the ledger per symbol, marking what is still held to the latest prices
+pub fn symbol_breakdown(
+    opening: &[OwnedPosition],
+    ledger: &[Fill],
+    method: CostBasis,
+    latest_prices: &HashMap<String, f64>,
+) -> Vec<SymbolPnl> {
+    let mut lots: BTreeMap<String, Lots> = BTreeMap::new();
+    let mut breakdown: BTreeMap<String, SymbolPnl> = BTreeMap::new();
+    let entry = |symbol: &str| SymbolPnl {
+        symbol: symbol.to_string(),
+        quantity: 0,
+        cost_basis: 0.0,
+        latest_price: 0.0,
+        realized_pnl: 0.0,
+        unrealized_pnl: 0.0,
+        fees: 0.0,
+        turnover: 0.0,
+        round_trips: 0,
+        closed_trades: Vec::new(),
+    };
+
+    for position in opening {
+        lots.entry(position.symbol.clone()).or_default().trade(position.quantity, position.average_cost, method);
+        breakdown.entry(position.symbol.clone()).or_insert_with(|| entry(&position.symbol));
+    }
+    for fill in ledger {
+        let symbol_lots = lots.entry(fill.symbol.clone()).or_default();
+        let pnl = breakdown.entry(fill.symbol.clone()).or_insert_with(|| entry(&fill.symbol));
+        pnl.turnover += fill.price * fill.quantity as f64;
+        pnl.fees += fill.fees;
+        let quantity = match fill.side {
+            Side::Buy => fill.quantity as i64,
+            Side::Sell => -(fill.quantity as i64),
+        };
+        let (realized, closed) = symbol_lots.trade(quantity, fill.price, method);
+        if closed {
+            pnl.realized_pnl += realized;
+            pnl.closed_trades.push(realized - fill.fees);
+            if symbol_lots.quantity() == 0 {
+                pnl.round_trips += 1;
+            }
+        }
+    }
+
+    breakdown.into_values().map(|mut pnl| {
+        let symbol_lots = &lots[&pnl.symbol];
+        pnl.quantity = symbol_lots.quantity();
+        pnl.cost_basis = symbol_lots.cost();
+        // Without a quote the position is marked at cost
+        pnl.latest_price = latest_prices.get(&pnl.symbol).copied()
+            .unwrap_or_else(|| if pnl.quantity != 0 { pnl.cost_basis / pnl.quantity as f64 } else { 0.0 });
+        pnl.unrealized_pnl = pnl.latest_price * pnl.quantity as f64 - pnl.cost_basis;
+        pnl
+    }).collect()
+}
@@ -1,150 +1,249 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::{broadcast, RwLock, Barrier, Mutex};
-use tokio::time::{sleep, Duration, timeout}; // Import the sleep, Duration, and timeout modules
+use tokio::time::Duration;
 use futures::future::join_all; // Import join_all from the futures crate
 
 mod stock_listener;
 use stock_listener::{run_stock_listener, StockStore};
 
 mod brokers;
-use brokers::run_brokers;
+use brokers::{run_brokers, TraderSetup};
+
+mod risk;
+mod borrow;
+mod margin;
+mod fees;
 
 mod traders;
 use crate::traders::Trader;
+use crate::color::print_colored;
+mod strategy;
+use strategy::build_strategy;
 mod models;
 mod portfolio;
+mod ledger;
+mod order_tracker;
+mod analytics;
+mod report;
+use report::SessionReport;
+use analytics::{record_equity, run_equity_snapshots};
 use portfolio::display_all_portfolios;
 mod color; // Add this line to reference the color module
 
+mod price_model;
 mod stock_send;
 use stock_send::run_stock_send;
 
+mod replay;
+use replay::run_replay;
+
+mod matching_engine;
+
 mod order_sender;
 use order_sender::run_order_sender;
 
 mod order_status_receiver;
 use order_status_receiver::run_order_status_receiver;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (tx, _rx) = broadcast::channel(16);
+mod config;
+use config::{BrokerConfig, FeedSource, MarketConfig, TransportKind};
+
+mod clock;
+use clock::{build_runtime, Clock};
+
+mod seed;
+use seed::SeedSource;
+
+mod transport;
+use transport::{AmqpTransport, InMemoryTransport, Transport};
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Usage: rust_asm [config.json] [--in-memory], see market.example.json for the config format
+    let config_path = std::env::args().skip(1).find(|arg| !arg.starts_with("--")).unwrap_or_else(|| "market.json".to_string());
+    let mut config = MarketConfig::load(&config_path)?;
+    if std::env::args().any(|arg| arg == "--in-memory") {
+        config.transport = TransportKind::InMemory;
+    }
+    config.validate()?;
+
+    // The runtime depends on the clock mode, so it is built by hand instead of with #[tokio::main]
+    let runtime = build_runtime(config.clock, config.seed.is_some())?;
+    runtime.block_on(run_market(config))
+}
+
+async fn run_market(config: MarketConfig) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let clock = Clock::new(config.clock);
+
+    // Run everything in this process with the in-memory transport, otherwise go through RabbitMQ
+    let transport: Arc<dyn Transport> = match config.transport {
+        TransportKind::InMemory => Arc::new(InMemoryTransport::new()),
+        TransportKind::Amqp => Arc::new(AmqpTransport::connect(&config.amqp_url).await?),
+    };
+
+    let seeds = SeedSource::new(config.seed);
+    if let Some(seed) = config.seed {
+        println!("Running with seed {}", seed);
+    }
+
+    // Large enough to hold a full tick of the feed, so brokers do not miss symbols
+    let (tx, _rx) = broadcast::channel(config.symbols.len().max(16));
     // Initialize the stock store
     let stock_store: StockStore = Arc::new(RwLock::new(HashMap::new()));
 
-    let barrier = Arc::new(Barrier::new(6)); // 5 brokers + 1 for the main task
+    let barrier = Arc::new(Barrier::new(config.brokers.len() + 1)); // All brokers + 1 for the main task
 
     let tx_clone = tx.clone();
     let stock_store_clone = stock_store.clone();
     let barrier_clone = barrier.clone();
 
-    // Create traders
-    let traders: Vec<Arc<Mutex<Trader>>> = vec![
-        Arc::new(Mutex::new(Trader::new("B001-T001".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B001-T002".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B001-T003".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B002-T001".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B002-T002".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B002-T003".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B003-T001".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B003-T002".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B003-T003".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B004-T001".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B004-T002".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B004-T003".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B005-T001".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B005-T002".to_string()))),
-        Arc::new(Mutex::new(Trader::new("B005-T003".to_string()))),
-        // Add more traders as needed
-    ];
-
-    // Clone the traders vector before passing it to run_brokers
-    let traders_clone = traders.clone();
-
+    // Create the traders declared under each broker, late joiners are connected once the market is running
+    let mut brokers: Vec<(BrokerConfig, Vec<TraderSetup>)> = Vec::new();
+    let mut late_joiners: Vec<(String, u64, TraderSetup)> = Vec::new();
+    let mut traders: Vec<Arc<Mutex<Trader>>> = Vec::new();
+    for broker in &config.brokers {
+        let mut opening_traders = Vec::new();
+        for trader_config in &broker.traders {
+            let trader = Arc::new(Mutex::new(Trader::from_config(trader_config, &broker.fees)));
+            let strategy = build_strategy(&trader_config.strategy, seeds.rng_for(&format!("trader:{}", trader_config.id)));
+            traders.push(trader.clone());
+            match trader_config.join_after_secs {
+                Some(delay) => late_joiners.push((broker.id.clone(), delay, (trader, strategy))),
+                None => opening_traders.push((trader, strategy)),
+            }
+        }
+        brokers.push((broker.clone(), opening_traders));
+    }
 
     // Run brokers
-    let brokers_handle = tokio::spawn(async move {
-        run_brokers(tx, barrier, traders_clone).await;
-    });
+    let broker_handles = run_brokers(transport.clone(), config.queues.clone(), tx, barrier, clock, stock_store.clone(), &config.feed, brokers).await;
 
     // Wait for all brokers to start
     barrier_clone.wait().await;
 
+    for (broker_id, delay, setup) in late_joiners {
+        let Some(handle) = broker_handles.iter().find(|h| h.broker_id == broker_id).cloned() else { continue };
+        tokio::spawn(async move {
+            clock.sleep(Duration::from_secs(delay)).await;
+            if let Err(e) = handle.join(setup).await {
+                eprintln!("{}", e);
+            }
+        });
+    }
+
     //sleep(Duration::from_secs(2)).await;
 
-    // Start the stock sender
+    // Start the stock sender, either simulating prices or replaying history
+    let stock_send_transport = transport.clone();
+    let stock_send_queues = config.queues.clone();
+    let feed = config.feed.clone();
+    let symbols = config.symbols.clone();
+    let feed_rng = seeds.rng_for("feed");
     let stock_send_handle = tokio::spawn(async move {
-        if let Err(e) = run_stock_send().await {
+        let result = match feed.source.clone() {
+            FeedSource::Model => run_stock_send(stock_send_transport, stock_send_queues, feed, symbols, feed_rng, clock).await,
+            FeedSource::Replay { files } => run_replay(stock_send_transport, stock_send_queues, files, clock).await,
+        };
+        if let Err(e) = result {
             eprintln!("RabbitMQ Sender Error: {:?}", e);
         }
     });
 
     // Spawn the stock listener asynchronously
+    let stock_listener_transport = transport.clone();
+    let stock_listener_queues = config.queues.clone();
     let stock_listener_handle = tokio::spawn(async move {
-        if let Err(e) = run_stock_listener(tx_clone, stock_store_clone).await {
+        if let Err(e) = run_stock_listener(stock_listener_transport, stock_listener_queues, tx_clone, stock_store_clone).await {
             eprintln!("RabbitMQ Listener Error: {:?}", e);
         }
     });
 
-    // Start the order sender
+    // Start the order sender, DAY orders expire when the session closes
+    let order_sender_transport = transport.clone();
+    let order_sender_queues = config.queues.clone();
+    let session_close = clock.now_millis() + config.session_secs * 1000;
     let order_sender_handle = tokio::spawn(async move {
-        if let Err(e) = run_order_sender().await {
+        if let Err(e) = run_order_sender(order_sender_transport, order_sender_queues, clock, session_close).await {
             eprintln!("RabbitMQ Order Sender Error: {:?}", e);
         }
     });
 
     // Start the order status receiver
+    let order_status_receiver_transport = transport.clone();
+    let order_status_receiver_queues = config.queues.clone();
     let order_status_receiver_handle = tokio::spawn(async move {
-        if let Err(e) = run_order_status_receiver().await {
+        if let Err(e) = run_order_status_receiver(order_status_receiver_transport, order_status_receiver_queues).await {
             eprintln!("RabbitMQ Order Status Receiver Error: {:?}", e);
         }
     });
 
-    // Run the system for 60 seconds
-    let result = timeout(Duration::from_secs(60), async {
-        tokio::signal::ctrl_c().await?;
-        Ok::<(), Box<dyn std::error::Error>>(())
-    }).await;
-
-    match result {
-        Ok(_) => println!("Trading Market Closed due to stock provider ran away"),
-        Err(_) => println!("Trading Market Closed at the end day."),
+    // Snapshot every trader's equity through the session
+    let snapshot_handle = tokio::spawn(run_equity_snapshots(
+        traders.clone(),
+        stock_store.clone(),
+        clock,
+        Duration::from_millis(config.snapshot_interval_ms),
+    ));
+
+    // Run the system for the configured session length of simulated time
+    tokio::select! {
+        _ = clock.sleep(Duration::from_secs(config.session_secs)) => println!("Trading Market Closed at the end day."),
+        _ = tokio::signal::ctrl_c() => println!("Trading Market Closed due to stock provider ran away"),
     }
 
-    // Stop the stock listener and brokers
+    // Stop the market data
     stock_listener_handle.abort();
     stock_send_handle.abort();
-    order_sender_handle.abort();
-    order_status_receiver_handle.abort();
-    brokers_handle.abort();
+    snapshot_handle.abort();
 
     // Wait for the tasks to be aborted
     let _ = stock_listener_handle.await;
     let _ = stock_send_handle.await;
+    let _ = snapshot_handle.await;
+
+    // Leave the order flow running for a second so the expiry of DAY orders reaches the traders
+    clock.sleep(Duration::from_secs(1)).await;
+    order_sender_handle.abort();
+    order_status_receiver_handle.abort();
     let _ = order_sender_handle.await;
     let _ = order_status_receiver_handle.await;
-    let _ = brokers_handle.await;
-
-    // Sleep for 3 seconds before managing pending orders
-    sleep(Duration::from_secs(1)).await;
     println!("Broker managing pending orders returned to Trader's cash...");
-    sleep(Duration::from_secs(2)).await;
+    clock.sleep(Duration::from_secs(2)).await;
 
-     // Cancel pending orders and return cash to traders
-     for trader in &traders {
+    // Cancel what is still working, good-till-cancelled orders are not carried to another session yet
+    for trader in &traders {
         let mut trader = trader.lock().await;
         trader.cancel_pending_orders();
+        if let Err(e) = trader.reconcile() {
+            print_colored(&format!("Session closed with unbalanced books: {}", e), "red");
+        }
     }
 
+    // Final snapshot once every order has been settled
+    record_equity(&traders, &stock_store, &clock).await;
+
     // Sleep for 3 seconds before displaying portfolios
     println!("Marketing closing generating all trader performance...");
-    sleep(Duration::from_secs(3)).await;
+    clock.sleep(Duration::from_secs(3)).await;
 
     // Display all trader portfolios
     let trader_refs: Vec<_> = join_all(traders.iter().map(|t| async {
         t.lock().await.clone()
     })).await;
-    display_all_portfolios(&trader_refs,  &stock_store).await;
+
+    // Ratios are annualised against model time: one feed tick covers `tick_years`
+    let periods_per_year = config.feed.tick_interval_ms as f64 / (config.feed.tick_years * config.snapshot_interval_ms as f64);
+    let (portfolios, performance) = display_all_portfolios(&trader_refs, &stock_store, config.cost_basis, periods_per_year).await;
+
+    // Export the session for notebooks and dashboards
+    if let Some(report_dir) = &config.report_dir {
+        let report = SessionReport::new(clock.now_millis(), config.session_secs, config.seed, config.cost_basis, &trader_refs, portfolios, performance);
+        match report.write(Path::new(report_dir)) {
+            Ok(()) => println!("Session report written to {}", report_dir),
+            Err(e) => eprintln!("Failed to write session report to {}: {}", report_dir, e),
+        }
+    }
 
     Ok(())
 }
\ No newline at end of file
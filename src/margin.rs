@@ -0,0 +1,161 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::color::print_colored;
+use crate::config::MarginConfig;
+use crate::models::{OrderRequest, OrderType, Stock};
+use crate::order_tracker::OrderState;
+use crate::traders::Trader;
+
+// Runs the margin accounts of a broker's traders: new orders must fit the buying power left over
+// the initial margin, and every price update checks the equity against the maintenance margin.
+// Prices are the marks in the stock store, positions never quoted are marked at cost.
+#[derive(Debug)]
+pub struct MarginDesk {
+    terms: MarginConfig,
+    calls: HashMap<String, u64>, // Traders under a margin call, with when it was issued
+}
+
+impl MarginDesk {
+    pub fn new(terms: MarginConfig) -> Self {
+        Self { terms, calls: HashMap::new() }
+    }
+
+    // Initial and maintenance rate of a symbol
+    fn rates(&self, symbol: &str) -> (f64, f64) {
+        self.terms.symbols.iter()
+            .find(|rates| rates.symbol == symbol)
+            .map_or((self.terms.initial, self.terms.maintenance), |rates| (rates.initial, rates.maintenance))
+    }
+
+    // Value of a symbol's position or order can only be judged at a price, the mark or else the cost
+    fn mark(trader: &Trader, marks: &HashMap<String, Stock>, symbol: &str) -> f64 {
+        marks.get(symbol).map(|stock| stock.price)
+            .or_else(|| trader.portfolio.iter().find(|p| p.symbol == symbol).map(|p| p.average_cost))
+            .unwrap_or(0.0)
+    }
+
+    // Shares of each symbol held and bought or sold short by open orders, leaving out one order.
+    // Sells first close the shares held, only the rest go short.
+    fn exposure(trader: &Trader, excluding: &str) -> BTreeMap<String, (u32, u32)> {
+        let mut orders: BTreeMap<String, (u32, u32)> = BTreeMap::new();
+        let open = trader.orders.open_orders().filter(|o| o.order.order_id != excluding && o.state != OrderState::Held);
+        for tracked in open {
+            let (buying, selling) = orders.entry(tracked.order.stock_symbol.clone()).or_default();
+            if tracked.order.order_type.is_buy() { *buying += tracked.leaves_quantity } else { *selling += tracked.leaves_quantity }
+        }
+        for position in &trader.portfolio {
+            orders.entry(position.symbol.clone()).or_default();
+        }
+        orders.into_iter().map(|(symbol, (buying, selling))| {
+            let position = trader.position(&symbol);
+            let (held, short) = (u32::try_from(position).unwrap_or(0), u32::try_from(-position).unwrap_or(0));
+            (symbol, (held + buying, short + selling.saturating_sub(held)))
+        }).collect()
+    }
+
+    // Value of `symbol` the account can still buy or sell short: the equity left over the initial
+    // margin on everything it holds and has open except one order, at the symbol's initial rate
+    fn buying_power(&self, trader: &Trader, marks: &HashMap<String, Stock>, symbol: &str, excluding: &str) -> f64 {
+        let required: f64 = Self::exposure(trader, excluding).iter()
+            .map(|(symbol, (long, short))| (long + short) as f64 * Self::mark(trader, marks, symbol) * self.rates(symbol).0)
+            .sum();
+        (trader.equity(marks) - required).max(0.0) / self.rates(symbol).0
+    }
+
+    // Refuse a margin account's new order worth more than its buying power.
+    // Selling shares held frees margin, only what a sell sells short needs any.
+    pub fn check(&self, request: &OrderRequest, trader: &Trader, marks: &HashMap<String, Stock>) -> Result<(), String> {
+        let OrderRequest::New(order) = request else { return Ok(()) };
+        if !trader.margin_account {
+            return Ok(());
+        }
+        let shares = if order.order_type.is_buy() {
+            order.quantity
+        } else {
+            let held = u32::try_from(trader.position(&order.stock_symbol)).unwrap_or(0);
+            let other_sells = trader.orders.promised_quantity(&order.stock_symbol).saturating_sub(order.quantity);
+            order.quantity.saturating_sub(held.saturating_sub(other_sells))
+        };
+        if shares == 0 {
+            return Ok(());
+        }
+        let price = order.limit_price.unwrap_or_else(|| Self::mark(trader, marks, &order.stock_symbol));
+        let value = price * shares as f64;
+        let buying_power = self.buying_power(trader, marks, &order.stock_symbol, &order.order_id);
+        if value > buying_power {
+            return Err(format!("Order value ${:.2} is over the ${:.2} buying power", value, buying_power));
+        }
+        Ok(())
+    }
+
+    // Equity the positions must be backed by
+    fn maintenance_margin(&self, trader: &Trader, marks: &HashMap<String, Stock>) -> f64 {
+        trader.portfolio.iter()
+            .map(|p| p.quantity.unsigned_abs() as f64 * Self::mark(trader, marks, &p.symbol) * self.rates(&p.symbol).1)
+            .sum()
+    }
+
+    // Check a margin account against its maintenance margin after a price update. An account that
+    // falls short gets a margin call; once the grace period runs out its resting orders are pulled
+    // and its positions closed, largest first, until what is left is covered.
+    pub fn monitor(&mut self, trader: &mut Trader, marks: &HashMap<String, Stock>, now: u64) -> Vec<OrderRequest> {
+        if !trader.margin_account {
+            return Vec::new();
+        }
+        let equity = trader.equity(marks);
+        let required = self.maintenance_margin(trader, marks);
+        if equity >= required {
+            if self.calls.remove(&trader.id).is_some() {
+                print_colored(&format!("Trader {} met its margin call with ${:.2} of equity.", trader.id, equity), "green");
+            }
+            return Vec::new();
+        }
+        let issued = *self.calls.entry(trader.id.clone()).or_insert_with(|| {
+            print_colored(
+                &format!("Margin call for Trader {}: equity ${:.2} is below the ${:.2} maintenance margin.", trader.id, equity, required),
+                "red"
+            );
+            now
+        });
+        if now.saturating_sub(issued) < self.terms.grace_period_secs * 1000 {
+            return Vec::new();
+        }
+
+        // Market orders are done trading by the time a cancel would reach them
+        let resting: Vec<String> = trader.pending_orders()
+            .filter(|o| !matches!(o.order_type, OrderType::MarketBuy | OrderType::MarketSell))
+            .map(|o| o.order_id.clone())
+            .collect();
+        let mut requests: Vec<OrderRequest> = resting.iter().filter_map(|order_id| trader.request_cancel(order_id)).collect();
+
+        let mut positions: Vec<(String, i64, f64)> = trader.portfolio.iter()
+            .map(|p| (p.symbol.clone(), p.quantity, Self::mark(trader, marks, &p.symbol)))
+            .collect();
+        positions.sort_by(|(_, a, a_price), (_, b, b_price)| (b.abs() as f64 * b_price).total_cmp(&(a.abs() as f64 * a_price)));
+        let mut shortfall = required - equity;
+        for (symbol, quantity, price) in positions {
+            let maintenance = self.rates(&symbol).1;
+            if shortfall <= 0.0 || price <= 0.0 {
+                continue;
+            }
+            // Shares not already on their way out through another order
+            let closable = if quantity > 0 {
+                trader.available_quantity(&symbol)
+            } else {
+                let buying: u32 = trader.orders.open_orders()
+                    .filter(|o| o.order.stock_symbol == symbol && o.order.order_type.is_buy())
+                    .map(|o| o.leaves_quantity)
+                    .sum();
+                u32::try_from(-quantity).unwrap_or(0).saturating_sub(buying)
+            };
+            let shares = ((shortfall / (price * maintenance)).ceil() as u32).min(closable);
+            if shares > 0 {
+                match trader.liquidation_order(&symbol, shares) {
+                    Ok(request) => requests.push(request),
+                    Err(e) => print_colored(&format!("Trader {} could not liquidate {}: {}", trader.id, symbol, e), "red"),
+                }
+                shortfall -= shares as f64 * price * maintenance;
+            }
+        }
+        requests
+    }
+}
@@ -0,0 +1,708 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use crate::clock::Clock;
+use crate::models::{BookDepth, DepthLevel, Liquidity, Order, OrderRequest, OrderStatus, OrderStatusUpdate, OrderType, Stock, TimeInForce, Trail};
+
+// Prices are kept as integer ticks (cents) so they can be used as ordered map keys
+const TICKS_PER_UNIT: f64 = 100.0;
+
+fn to_ticks(price: f64) -> i64 {
+    (price * TICKS_PER_UNIT).round() as i64
+}
+
+fn from_ticks(ticks: i64) -> f64 {
+    ticks as f64 / TICKS_PER_UNIT
+}
+
+fn report(order: &Order, status: OrderStatus, fill: Option<(u32, f64)>, leaves_quantity: u32, timestamp: u64) -> OrderStatusUpdate {
+    let (fill_quantity, fill_price) = match fill {
+        Some((quantity, price)) => (quantity, Some(price)),
+        None => (0, None),
+    };
+    OrderStatusUpdate {
+        order_id: order.order_id.clone(),
+        trader_id: order.trader_id.clone(),
+        stock_symbol: order.stock_symbol.clone(),
+        status,
+        fill_quantity,
+        fill_price,
+        cumulative_quantity: order.quantity - leaves_quantity,
+        leaves_quantity,
+        timestamp,
+        reason: None,
+        liquidity: None,
+        fees: 0.0,
+        reject_code: None,
+    }
+}
+
+// Report of a fill, saying whether the order made or took the liquidity
+fn filled(order: &Order, quantity: u32, price: f64, leaves_quantity: u32, liquidity: Liquidity, timestamp: u64) -> OrderStatusUpdate {
+    let mut update = report(order, fill_status(leaves_quantity), Some((quantity, price)), leaves_quantity, timestamp);
+    update.liquidity = Some(liquidity);
+    update
+}
+
+// Report that ends an order, saying why
+fn closed(order: &Order, status: OrderStatus, leaves_quantity: u32, reason: &str, timestamp: u64) -> OrderStatusUpdate {
+    let mut update = report(order, status, None, leaves_quantity, timestamp);
+    update.reason = Some(reason.to_string());
+    update
+}
+
+// Refusal of a cancel or replace, the order it names may not exist so only the request's ids are known
+fn request_rejected(order_id: &str, trader_id: &str, stock_symbol: &str, reason: &str, timestamp: u64) -> OrderStatusUpdate {
+    OrderStatusUpdate {
+        order_id: order_id.to_string(),
+        trader_id: trader_id.to_string(),
+        stock_symbol: stock_symbol.to_string(),
+        status: OrderStatus::CancelRejected,
+        fill_quantity: 0,
+        fill_price: None,
+        cumulative_quantity: 0,
+        leaves_quantity: 0,
+        timestamp,
+        reason: Some(reason.to_string()),
+        liquidity: None,
+        fees: 0.0,
+        reject_code: None,
+    }
+}
+
+fn fill_status(leaves_quantity: u32) -> OrderStatus {
+    if leaves_quantity == 0 { OrderStatus::Filled } else { OrderStatus::PartiallyFilled }
+}
+
+#[derive(Debug, Clone)]
+struct RestingOrder {
+    order: Order,
+    remaining: u32,
+    visible: u32, // Part of `remaining` shown in the book, less than all of it for icebergs
+}
+
+impl RestingOrder {
+    fn new(order: Order, remaining: u32) -> Self {
+        let visible = order.display_quantity.map_or(remaining, |display| display.min(remaining));
+        Self { order, remaining, visible }
+    }
+}
+
+// A stop order held outside the book until the price reaches it
+#[derive(Debug, Clone)]
+struct StopOrder {
+    order: Order,
+    watermark: Option<f64>, // Best price seen since submission, what a trailing stop follows
+}
+
+impl StopOrder {
+    fn stop_price(&self) -> Option<f64> {
+        match self.order.trail {
+            Some(trail) => self.watermark.map(|watermark| trail.stop_from(watermark, self.order.order_type.is_buy())),
+            None => self.order.stop_price,
+        }
+    }
+
+    // Follow the price, then report whether it reached the stop
+    fn observe(&mut self, price: f64) -> bool {
+        let is_buy = self.order.order_type.is_buy();
+        self.watermark = Some(match self.watermark {
+            Some(watermark) if is_buy => watermark.min(price),
+            Some(watermark) => watermark.max(price),
+            None => price,
+        });
+        self.stop_price().is_some_and(|stop| if is_buy { price >= stop } else { price <= stop })
+    }
+}
+
+// Why an order's limit price does not fit its type, the book trades an order without one at any price
+fn invalid_limit(order: &Order) -> Option<&'static str> {
+    match order.order_type {
+        OrderType::LimitBuy | OrderType::LimitSell if order.limit_price.is_none() => Some("Limit orders need a limit price"),
+        OrderType::MarketBuy | OrderType::MarketSell | OrderType::StopBuy | OrderType::StopSell
+        | OrderType::TrailingStopBuy | OrderType::TrailingStopSell if order.limit_price.is_some() => {
+            Some("Market and stop orders cannot carry a limit price")
+        }
+        _ => None,
+    }
+}
+
+// Why a stop order cannot be accepted, if it cannot
+fn invalid_stop(order: &Order) -> Option<&'static str> {
+    let positive = |value: Option<f64>| value.is_some_and(|v| v > 0.0);
+    match order.order_type {
+        OrderType::StopBuy | OrderType::StopSell if !positive(order.stop_price) => Some("Stop orders need a positive stop price"),
+        OrderType::StopLimitBuy | OrderType::StopLimitSell if !positive(order.stop_price) || order.limit_price.is_none() => {
+            Some("Stop-limit orders need a positive stop price and a limit price")
+        }
+        OrderType::TrailingStopBuy | OrderType::TrailingStopSell => match order.trail {
+            Some(Trail::Amount(distance) | Trail::Percent(distance)) if distance > 0.0 => None,
+            _ => Some("Trailing stops need a positive trail"),
+        },
+        _ => None,
+    }
+}
+
+// Limit order book for a single symbol.
+// Each side maps a price level to the orders resting there in arrival order (price-time priority).
+#[derive(Debug)]
+pub struct OrderBook {
+    bids: BTreeMap<i64, VecDeque<RestingOrder>>,
+    asks: BTreeMap<i64, VecDeque<RestingOrder>>,
+    index: HashMap<String, (bool, i64)>, // Side (true for bids) and price level of every resting order
+    stops: Vec<StopOrder>, // Untriggered stops in arrival order
+    expiries: BTreeSet<(u64, String)>, // When each working order that can expire does, soonest first
+    last_price: Option<f64>, // Latest price from the stock feed
+    session_close: u64, // When DAY orders expire
+}
+
+impl OrderBook {
+    pub fn new(session_close: u64) -> Self {
+        Self {
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            index: HashMap::new(),
+            stops: Vec::new(),
+            expiries: BTreeSet::new(),
+            last_price: None,
+            session_close,
+        }
+    }
+
+    // Key of an order in the expiry index, none if it only ends by trading or being cancelled
+    fn expiry_key(&self, order: &Order) -> Option<(u64, String)> {
+        order.time_in_force.expires_at(self.session_close).map(|at| (at, order.order_id.clone()))
+    }
+
+    pub fn submit(&mut self, order: Order, timestamp: u64) -> Vec<OrderStatusUpdate> {
+        let session_close = self.session_close;
+        if order.quantity == 0 || order.limit_price.is_some_and(|price| price <= 0.0) {
+            return vec![closed(&order, OrderStatus::Rejected, order.quantity, "Quantity and limit price must be positive", timestamp)];
+        }
+        if let Some(reason) = invalid_limit(&order) {
+            return vec![closed(&order, OrderStatus::Rejected, order.quantity, reason, timestamp)];
+        }
+        if order.display_quantity == Some(0) {
+            return vec![closed(&order, OrderStatus::Rejected, order.quantity, "Display quantity must be positive", timestamp)];
+        }
+        if timestamp >= session_close {
+            return vec![closed(&order, OrderStatus::Rejected, order.quantity, "Market is closed", timestamp)];
+        }
+        if order.time_in_force.expires_at(session_close).is_some_and(|expire_at| expire_at <= timestamp) {
+            return vec![closed(&order, OrderStatus::Rejected, order.quantity, "Good-till-date has already passed", timestamp)];
+        }
+
+        let mut reports = Vec::new();
+        if order.order_type.is_stop() {
+            if let Some(reason) = invalid_stop(&order) {
+                return vec![closed(&order, OrderStatus::Rejected, order.quantity, reason, timestamp)];
+            }
+            reports.push(report(&order, OrderStatus::New, None, order.quantity, timestamp));
+            self.expiries.extend(self.expiry_key(&order));
+            self.stops.push(StopOrder { order, watermark: None });
+            // A stop already through the market triggers straight away
+            if let Some(price) = self.last_price {
+                reports.extend(self.on_price(price, timestamp));
+            }
+            return reports;
+        }
+
+        let remaining = order.quantity;
+        self.execute(order, remaining, timestamp, true, &mut reports);
+        reports
+    }
+
+    // Match `remaining` shares of an order against the book and rest or cancel what is left.
+    // `acknowledge` sends New when the order rests without trading, a replaced order was already acknowledged.
+    fn execute(&mut self, order: Order, mut remaining: u32, timestamp: u64, acknowledge: bool, reports: &mut Vec<OrderStatusUpdate>) {
+        let is_buy = order.order_type.is_buy();
+        // Market orders carry no limit and sweep the book until filled or the book is empty
+        let limit = order.limit_price.map(to_ticks);
+        if order.time_in_force == TimeInForce::Fok && self.available(is_buy, limit) < remaining {
+            reports.push(closed(&order, OrderStatus::Cancelled, remaining, "Fill-or-kill order could not be filled in full", timestamp));
+            return;
+        }
+
+        let opposite = if is_buy { &mut self.asks } else { &mut self.bids };
+        while remaining > 0 {
+            let best = if is_buy {
+                opposite.keys().next().copied()
+            } else {
+                opposite.keys().next_back().copied()
+            };
+            let Some(level_price) = best else { break };
+            if let Some(limit) = limit {
+                if (is_buy && level_price > limit) || (!is_buy && level_price < limit) {
+                    break;
+                }
+            }
+
+            let level = opposite.get_mut(&level_price).expect("price level exists");
+            while remaining > 0 {
+                let Some(resting) = level.front_mut() else { break };
+                // Only the visible slice of an iceberg trades at a time
+                let quantity = remaining.min(resting.visible);
+                let price = from_ticks(level_price);
+                remaining -= quantity;
+                resting.remaining -= quantity;
+                resting.visible -= quantity;
+
+                // Both sides trade at the resting order's price
+                reports.push(filled(&resting.order, quantity, price, resting.remaining, Liquidity::Maker, timestamp));
+                reports.push(filled(&order, quantity, price, remaining, Liquidity::Taker, timestamp));
+
+                if resting.remaining == 0 {
+                    self.index.remove(&resting.order.order_id);
+                    if let Some(at) = resting.order.time_in_force.expires_at(self.session_close) {
+                        self.expiries.remove(&(at, resting.order.order_id.clone()));
+                    }
+                    level.pop_front();
+                } else if resting.visible == 0 {
+                    // The next slice of an iceberg joins the back of the queue
+                    let resting = level.pop_front().expect("front order exists");
+                    level.push_back(RestingOrder::new(resting.order, resting.remaining));
+                }
+            }
+            if level.is_empty() {
+                opposite.remove(&level_price);
+            }
+        }
+
+        if remaining > 0 {
+            match limit {
+                Some(_) if order.time_in_force == TimeInForce::Ioc => {
+                    reports.push(closed(&order, OrderStatus::Cancelled, remaining, "Immediate-or-cancel remainder", timestamp));
+                }
+                Some(ticks) => {
+                    // Orders that already traded were acknowledged by their partial fill
+                    if acknowledge && remaining == order.quantity {
+                        reports.push(report(&order, OrderStatus::New, None, remaining, timestamp));
+                    }
+                    self.index.insert(order.order_id.clone(), (is_buy, ticks));
+                    self.expiries.extend(self.expiry_key(&order));
+                    let own_side = if is_buy { &mut self.bids } else { &mut self.asks };
+                    own_side.entry(ticks).or_default().push_back(RestingOrder::new(order, remaining));
+                }
+                None => {
+                    // Whatever a market order could not take from the book is cancelled
+                    reports.push(report(&order, OrderStatus::Cancelled, None, remaining, timestamp));
+                }
+            }
+        }
+    }
+
+    // Shares a new order could take from the opposite side without going through its limit
+    fn available(&self, is_buy: bool, limit: Option<i64>) -> u32 {
+        let levels: Box<dyn Iterator<Item = (&i64, &VecDeque<RestingOrder>)>> = if is_buy {
+            Box::new(self.asks.iter().take_while(|(ticks, _)| limit.is_none_or(|limit| **ticks <= limit)))
+        } else {
+            Box::new(self.bids.iter().rev().take_while(|(ticks, _)| limit.is_none_or(|limit| **ticks >= limit)))
+        };
+        levels.flat_map(|(_, level)| level.iter()).map(|resting| resting.remaining).sum()
+    }
+
+    // Visible quantity at the best `levels` prices of each side
+    pub fn depth(&self, levels: usize) -> (Vec<DepthLevel>, Vec<DepthLevel>) {
+        let level = |(ticks, orders): (&i64, &VecDeque<RestingOrder>)| DepthLevel {
+            price: from_ticks(*ticks),
+            quantity: orders.iter().map(|resting| resting.visible).sum(),
+            orders: orders.len(),
+        };
+        (self.bids.iter().rev().take(levels).map(level).collect(), self.asks.iter().take(levels).map(level).collect())
+    }
+
+    // Earliest time a working order in this book expires
+    fn next_expiry(&self) -> Option<u64> {
+        self.expiries.first().map(|(at, _)| *at)
+    }
+
+    // Expire every working order whose time is up, soonest first, resting or a held stop
+    fn expire(&mut self, timestamp: u64) -> Vec<OrderStatusUpdate> {
+        let mut reports = Vec::new();
+        while let Some((at, order_id)) = self.expiries.first().filter(|(at, _)| *at <= timestamp).cloned() {
+            self.expiries.remove(&(at, order_id.clone()));
+            if let Some(resting) = self.take(&order_id) {
+                reports.push(closed(&resting.order, OrderStatus::Expired, resting.remaining, "Time in force ended", timestamp));
+            } else if let Some(position) = self.stops.iter().position(|s| s.order.order_id == order_id) {
+                let stop = self.stops.remove(position);
+                reports.push(closed(&stop.order, OrderStatus::Expired, stop.order.quantity, "Time in force ended", timestamp));
+            }
+        }
+        reports
+    }
+
+    fn untrack_expiry(&mut self, order: &Order) {
+        if let Some(key) = self.expiry_key(order) {
+            self.expiries.remove(&key);
+        }
+    }
+
+    // Move trailing stops with the price and release every stop it reaches, oldest first
+    pub fn on_price(&mut self, price: f64, timestamp: u64) -> Vec<OrderStatusUpdate> {
+        self.last_price = Some(price);
+        let mut triggered = Vec::new();
+        for mut stop in std::mem::take(&mut self.stops) {
+            if stop.observe(price) {
+                triggered.push(stop);
+            } else {
+                self.stops.push(stop);
+            }
+        }
+
+        let mut reports = Vec::new();
+        for stop in triggered {
+            self.untrack_expiry(&stop.order);
+            let mut order = stop.order;
+            order.order_type = order.order_type.released_type();
+            reports.push(report(&order, OrderStatus::Triggered, None, order.quantity, timestamp));
+            let remaining = order.quantity;
+            self.execute(order, remaining, timestamp, false, &mut reports);
+        }
+        reports
+    }
+
+    // Find a resting order, checking it belongs to the trader asking
+    fn find(&mut self, order_id: &str, trader_id: &str) -> Result<&mut RestingOrder, &'static str> {
+        let &(is_buy, ticks) = self.index.get(order_id).ok_or("Order is not resting in the book")?;
+        let side = if is_buy { &mut self.bids } else { &mut self.asks };
+        let resting = side.get_mut(&ticks).and_then(|level| level.iter_mut().find(|r| r.order.order_id == order_id))
+            .ok_or("Order is not resting in the book")?;
+        if resting.order.trader_id != trader_id {
+            return Err("Order belongs to another trader");
+        }
+        Ok(resting)
+    }
+
+    // Take a resting order out of the book
+    fn take(&mut self, order_id: &str) -> Option<RestingOrder> {
+        let (is_buy, ticks) = self.index.remove(order_id)?;
+        let side = if is_buy { &mut self.bids } else { &mut self.asks };
+        let level = side.get_mut(&ticks)?;
+        let position = level.iter().position(|r| r.order.order_id == order_id)?;
+        let resting = level.remove(position)?;
+        if level.is_empty() {
+            side.remove(&ticks);
+        }
+        self.untrack_expiry(&resting.order);
+        Some(resting)
+    }
+
+    pub fn cancel(&mut self, order_id: &str, trader_id: &str, stock_symbol: &str, timestamp: u64) -> Vec<OrderStatusUpdate> {
+        // Untriggered stops are not in the book yet
+        if let Some(position) = self.stops.iter().position(|s| s.order.order_id == order_id && s.order.trader_id == trader_id) {
+            let stop = self.stops.remove(position);
+            self.untrack_expiry(&stop.order);
+            return vec![report(&stop.order, OrderStatus::Cancelled, None, stop.order.quantity, timestamp)];
+        }
+        if let Err(reason) = self.find(order_id, trader_id) {
+            return vec![request_rejected(order_id, trader_id, stock_symbol, reason, timestamp)];
+        }
+        let resting = self.take(order_id).expect("order was found");
+        vec![report(&resting.order, OrderStatus::Cancelled, None, resting.remaining, timestamp)]
+    }
+
+    // Change the total quantity and limit price of a resting order. Shrinking it at the same
+    // price keeps its place in the queue, any other change sends it to the back of its new level.
+    pub fn replace(&mut self, order_id: &str, trader_id: &str, stock_symbol: &str, quantity: u32, limit_price: f64, timestamp: u64) -> Vec<OrderStatusUpdate> {
+        let resting = match self.find(order_id, trader_id) {
+            Ok(resting) => resting,
+            Err(reason) => return vec![request_rejected(order_id, trader_id, stock_symbol, reason, timestamp)],
+        };
+        let filled = resting.order.quantity - resting.remaining;
+        if quantity <= filled {
+            return vec![request_rejected(order_id, trader_id, stock_symbol, "New quantity does not exceed the quantity already filled", timestamp)];
+        }
+        if limit_price <= 0.0 {
+            return vec![request_rejected(order_id, trader_id, stock_symbol, "Limit price must be positive", timestamp)];
+        }
+
+        let remaining = quantity - filled;
+        let keeps_priority = resting.order.limit_price.map(to_ticks) == Some(to_ticks(limit_price)) && remaining <= resting.remaining;
+        if keeps_priority {
+            resting.order.quantity = quantity;
+            resting.remaining = remaining;
+            resting.visible = resting.visible.min(remaining);
+            return vec![report(&resting.order, OrderStatus::Replaced, None, remaining, timestamp)];
+        }
+
+        let mut order = self.take(order_id).expect("order was found").order;
+        order.quantity = quantity;
+        order.limit_price = Some(limit_price);
+        let mut reports = vec![report(&order, OrderStatus::Replaced, None, remaining, timestamp)];
+        // The new price may cross the book
+        self.execute(order, remaining, timestamp, false, &mut reports);
+        reports
+    }
+}
+
+// One order book per symbol, reports are stamped with simulated time.
+// DAY orders expire at `session_close`, after which new orders are refused.
+#[derive(Debug)]
+pub struct MatchingEngine {
+    books: HashMap<String, OrderBook>,
+    clock: Clock,
+    session_close: u64,
+}
+
+impl MatchingEngine {
+    pub fn new(clock: Clock, session_close: u64) -> Self {
+        Self { books: HashMap::new(), clock, session_close }
+    }
+
+    // Visible book of a symbol, for the depth feed
+    pub fn depth(&self, symbol: &str, levels: usize) -> BookDepth {
+        let (bids, asks) = self.books.get(symbol).map(|book| book.depth(levels)).unwrap_or_default();
+        BookDepth { symbol: symbol.to_string(), timestamp: self.clock.now_millis(), bids, asks }
+    }
+
+    // Earliest time an order in any book expires
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.books.values().filter_map(|book| book.next_expiry()).min()
+    }
+
+    fn book(&mut self, symbol: &str) -> &mut OrderBook {
+        let session_close = self.session_close;
+        self.books.entry(symbol.to_string()).or_insert_with(|| OrderBook::new(session_close))
+    }
+
+    // Expire every order whose time in force has ended, books in symbol order
+    pub fn expire(&mut self) -> Vec<OrderStatusUpdate> {
+        let timestamp = self.clock.now_millis();
+        let mut symbols: Vec<String> = self.books.keys().cloned().collect();
+        symbols.sort();
+        let mut reports = Vec::new();
+        for symbol in symbols {
+            reports.extend(self.books.get_mut(&symbol).expect("book exists").expire(timestamp));
+        }
+        reports
+    }
+
+    pub fn on_price(&mut self, stock: &Stock) -> Vec<OrderStatusUpdate> {
+        let timestamp = self.clock.now_millis();
+        self.book(&stock.symbol).on_price(stock.price, timestamp)
+    }
+
+    pub fn handle(&mut self, request: OrderRequest) -> Vec<OrderStatusUpdate> {
+        let timestamp = self.clock.now_millis();
+        match request {
+            OrderRequest::New(order) => {
+                self.book(&order.stock_symbol).submit(order, timestamp)
+            }
+            OrderRequest::Cancel { order_id, trader_id, stock_symbol } => {
+                self.book(&stock_symbol).cancel(&order_id, &trader_id, &stock_symbol, timestamp)
+            }
+            OrderRequest::Replace { order_id, trader_id, stock_symbol, quantity, limit_price } => {
+                self.book(&stock_symbol).replace(&order_id, &trader_id, &stock_symbol, quantity, limit_price, timestamp)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOSE: u64 = 1_000_000;
+
+    fn order(order_id: &str, order_type: OrderType, quantity: u32, limit_price: Option<f64>) -> Order {
+        Order {
+            order_id: order_id.to_string(),
+            trader_id: format!("trader-{}", order_id),
+            stock_symbol: "AAPL".to_string(),
+            order_type,
+            quantity,
+            limit_price,
+            stop_price: None,
+            trail: None,
+            time_in_force: TimeInForce::Day,
+            display_quantity: None,
+        }
+    }
+
+    // Status, fill quantity and price of each report on one order
+    fn reports_for(reports: &[OrderStatusUpdate], order_id: &str) -> Vec<(OrderStatus, u32, Option<f64>)> {
+        reports.iter().filter(|r| r.order_id == order_id).map(|r| (r.status, r.fill_quantity, r.fill_price)).collect()
+    }
+
+    #[test]
+    fn earlier_orders_at_a_price_trade_first() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(order("a", OrderType::LimitSell, 5, Some(10.0)), 1);
+        book.submit(order("b", OrderType::LimitSell, 5, Some(10.0)), 2);
+        book.submit(order("c", OrderType::LimitSell, 5, Some(9.5)), 3);
+
+        let reports = book.submit(order("buy", OrderType::LimitBuy, 8, Some(10.0)), 4);
+        // The better price goes first, then the oldest order at the next level
+        assert_eq!(reports_for(&reports, "c"), vec![(OrderStatus::Filled, 5, Some(9.5))]);
+        assert_eq!(reports_for(&reports, "a"), vec![(OrderStatus::PartiallyFilled, 3, Some(10.0))]);
+        assert!(reports_for(&reports, "b").is_empty());
+        assert_eq!(reports_for(&reports, "buy").last(), Some(&(OrderStatus::Filled, 3, Some(10.0))));
+    }
+
+    #[test]
+    fn a_partly_filled_limit_order_rests() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(order("ask", OrderType::LimitSell, 3, Some(10.0)), 1);
+        let reports = book.submit(order("bid", OrderType::LimitBuy, 10, Some(10.0)), 2);
+        assert_eq!(reports_for(&reports, "bid"), vec![(OrderStatus::PartiallyFilled, 3, Some(10.0))]);
+        assert_eq!(reports.last().map(|r| r.leaves_quantity), Some(7));
+
+        let (bids, asks) = book.depth(5);
+        assert!(asks.is_empty());
+        assert_eq!((bids[0].price, bids[0].quantity), (10.0, 7));
+    }
+
+    #[test]
+    fn a_market_order_sweeps_every_level_it_needs() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(order("a", OrderType::LimitSell, 2, Some(10.0)), 1);
+        book.submit(order("b", OrderType::LimitSell, 2, Some(10.5)), 2);
+        book.submit(order("c", OrderType::LimitSell, 2, Some(11.0)), 3);
+
+        let reports = book.submit(order("buy", OrderType::MarketBuy, 5, None), 4);
+        assert_eq!(
+            reports_for(&reports, "buy"),
+            vec![
+                (OrderStatus::PartiallyFilled, 2, Some(10.0)),
+                (OrderStatus::PartiallyFilled, 2, Some(10.5)),
+                (OrderStatus::Filled, 1, Some(11.0)),
+            ]
+        );
+        assert_eq!(book.depth(5).1[0].quantity, 1);
+    }
+
+    #[test]
+    fn a_market_order_into_an_empty_book_is_cancelled() {
+        let mut book = OrderBook::new(CLOSE);
+        let reports = book.submit(order("buy", OrderType::MarketBuy, 5, None), 1);
+        assert_eq!(reports_for(&reports, "buy"), vec![(OrderStatus::Cancelled, 0, None)]);
+        assert_eq!(reports[0].leaves_quantity, 5);
+        assert!(book.depth(5).0.is_empty());
+    }
+
+    #[test]
+    fn orders_without_the_price_their_type_needs_are_rejected() {
+        let mut book = OrderBook::new(CLOSE);
+        let reports = book.submit(order("limit", OrderType::LimitBuy, 5, None), 1);
+        assert_eq!(reports[0].status, OrderStatus::Rejected);
+        assert!(reports[0].reason.is_some());
+        let reports = book.submit(order("market", OrderType::MarketSell, 5, Some(10.0)), 1);
+        assert_eq!(reports[0].status, OrderStatus::Rejected);
+        assert!(reports[0].reason.is_some());
+    }
+
+    #[test]
+    fn cancel_takes_an_order_out_of_the_book() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(order("bid", OrderType::LimitBuy, 5, Some(10.0)), 1);
+
+        let reports = book.cancel("bid", "trader-other", "AAPL", 2);
+        assert_eq!(reports[0].status, OrderStatus::CancelRejected);
+
+        let reports = book.cancel("bid", "trader-bid", "AAPL", 3);
+        assert_eq!(reports_for(&reports, "bid"), vec![(OrderStatus::Cancelled, 0, None)]);
+        assert!(book.depth(5).0.is_empty());
+
+        let reports = book.cancel("bid", "trader-bid", "AAPL", 4);
+        assert_eq!(reports[0].status, OrderStatus::CancelRejected);
+    }
+
+    #[test]
+    fn fill_or_kill_trades_in_full_or_not_at_all() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(order("ask", OrderType::LimitSell, 3, Some(10.0)), 1);
+
+        let fok = |id: &str, quantity: u32| Order { time_in_force: TimeInForce::Fok, ..order(id, OrderType::LimitBuy, quantity, Some(10.0)) };
+        let reports = book.submit(fok("too-big", 4), 2);
+        assert_eq!(reports_for(&reports, "too-big"), vec![(OrderStatus::Cancelled, 0, None)]);
+        assert!(reports_for(&reports, "ask").is_empty());
+
+        let reports = book.submit(fok("fits", 3), 3);
+        assert_eq!(reports_for(&reports, "fits"), vec![(OrderStatus::Filled, 3, Some(10.0))]);
+        assert!(book.depth(5).1.is_empty());
+    }
+
+    #[test]
+    fn good_till_date_orders_expire_on_time() {
+        let mut book = OrderBook::new(CLOSE);
+        let gtd = |id: &str, expire_at: u64| Order { time_in_force: TimeInForce::Gtd { expire_at }, ..order(id, OrderType::LimitBuy, 5, Some(10.0)) };
+        book.submit(gtd("late", 500), 1);
+        book.submit(gtd("early", 200), 2);
+        book.submit(Order { time_in_force: TimeInForce::Gtc, ..order("gtc", OrderType::LimitBuy, 5, Some(9.0)) }, 3);
+        assert_eq!(book.next_expiry(), Some(200));
+
+        assert!(book.expire(199).is_empty());
+        let reports = book.expire(200);
+        assert_eq!(reports_for(&reports, "early"), vec![(OrderStatus::Expired, 0, None)]);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(book.next_expiry(), Some(500));
+
+        // A filled order no longer expires
+        book.submit(order("sell", OrderType::LimitSell, 5, Some(10.0)), 300);
+        assert_eq!(book.next_expiry(), None);
+        assert!(book.expire(CLOSE).is_empty());
+        assert_eq!(book.depth(5).0[0].price, 9.0);
+    }
+
+    #[test]
+    fn day_orders_expire_at_the_close() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(order("bid", OrderType::LimitBuy, 5, Some(10.0)), 1);
+        assert_eq!(book.next_expiry(), Some(CLOSE));
+        let reports = book.expire(CLOSE);
+        assert_eq!(reports_for(&reports, "bid"), vec![(OrderStatus::Expired, 0, None)]);
+        assert_eq!(book.next_expiry(), None);
+    }
+
+    #[test]
+    fn a_stop_triggers_and_then_fills() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(order("bid", OrderType::LimitBuy, 5, Some(9.0)), 1);
+        book.on_price(10.0, 2);
+        let stop = Order { stop_price: Some(9.5), ..order("stop", OrderType::StopSell, 3, None) };
+        let reports = book.submit(stop, 3);
+        assert_eq!(reports_for(&reports, "stop"), vec![(OrderStatus::New, 0, None)]);
+
+        // Above the stop nothing happens, at it the stop is released as a market sell
+        assert!(book.on_price(9.6, 4).is_empty());
+        let reports = book.on_price(9.5, 5);
+        assert_eq!(
+            reports_for(&reports, "stop"),
+            vec![(OrderStatus::Triggered, 0, None), (OrderStatus::Filled, 3, Some(9.0))]
+        );
+        assert_eq!(reports_for(&reports, "bid"), vec![(OrderStatus::PartiallyFilled, 3, Some(9.0))]);
+    }
+
+    #[test]
+    fn a_trailing_stop_follows_the_price() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(order("bid", OrderType::LimitBuy, 5, Some(9.0)), 1);
+        let trailing = Order { trail: Some(Trail::Amount(1.0)), ..order("trail", OrderType::TrailingStopSell, 2, None) };
+        book.submit(trailing, 2);
+
+        // The stop rises with the price to 11 and is not reached on the way back to 11.5
+        assert!(book.on_price(10.0, 3).is_empty());
+        assert!(book.on_price(12.0, 4).is_empty());
+        assert!(book.on_price(11.5, 5).is_empty());
+        let reports = book.on_price(11.0, 6);
+        assert_eq!(reports_for(&reports, "trail").last(), Some(&(OrderStatus::Filled, 2, Some(9.0))));
+    }
+
+    #[test]
+    fn an_iceberg_shows_a_slice_and_requeues_behind_later_orders() {
+        let mut book = OrderBook::new(CLOSE);
+        book.submit(Order { display_quantity: Some(2), ..order("iceberg", OrderType::LimitSell, 6, Some(10.0)) }, 1);
+        book.submit(order("plain", OrderType::LimitSell, 3, Some(10.0)), 2);
+        let asks = book.depth(5).1;
+        assert_eq!((asks[0].quantity, asks[0].orders), (5, 2));
+
+        // The first slice trades, the next one goes behind the plain order
+        let reports = book.submit(order("buy", OrderType::LimitBuy, 4, Some(10.0)), 3);
+        assert_eq!(reports_for(&reports, "iceberg"), vec![(OrderStatus::PartiallyFilled, 2, Some(10.0))]);
+        assert_eq!(reports_for(&reports, "plain"), vec![(OrderStatus::PartiallyFilled, 2, Some(10.0))]);
+
+        let reports = book.submit(order("more", OrderType::LimitBuy, 3, Some(10.0)), 4);
+        assert_eq!(reports_for(&reports, "plain"), vec![(OrderStatus::Filled, 1, Some(10.0))]);
+        assert_eq!(reports_for(&reports, "iceberg"), vec![(OrderStatus::PartiallyFilled, 2, Some(10.0))]);
+        let asks = book.depth(5).1;
+        assert_eq!((asks[0].quantity, asks[0].orders), (2, 1));
+    }
+}
@@ -21,6 +21,55 @@ pub struct Order {
     pub order_type: OrderType,
     pub quantity: u32,
     pub limit_price: Option<f64>, // Optional limit price for limit orders
+    #[serde(default)]
+    pub stop_price: Option<f64>, // Trigger price of stop and stop-limit orders
+    #[serde(default)]
+    pub trail: Option<Trail>, // Distance a trailing stop follows the best price by
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub display_quantity: Option<u32>, // Iceberg slice shown in the book, the rest of the quantity stays hidden
+}
+
+// How long an order keeps working, DAY unless asked otherwise
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    #[default]
+    Day, // Expires at the session close
+    Gtc, // Good till cancelled, outlives the session
+    Ioc, // Immediate or cancel: whatever does not trade on arrival is cancelled
+    Fok, // Fill or kill: trades in full on arrival or not at all
+    Gtd { expire_at: u64 }, // Good till date, milliseconds since the Unix epoch
+}
+
+impl TimeInForce {
+    // When an order left working expires, none if it only ends by being filled or cancelled
+    pub fn expires_at(&self, session_close: u64) -> Option<u64> {
+        match self {
+            TimeInForce::Day => Some(session_close),
+            TimeInForce::Gtd { expire_at } => Some(*expire_at),
+            TimeInForce::Gtc | TimeInForce::Ioc | TimeInForce::Fok => None,
+        }
+    }
+}
+
+// How far behind the best price since submission a trailing stop sits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Trail {
+    Amount(f64),
+    Percent(f64), // 5.0 trails by 5%
+}
+
+impl Trail {
+    // Stop price for a trailing order whose best price so far is `watermark`
+    pub fn stop_from(&self, watermark: f64, is_buy: bool) -> f64 {
+        let distance = match self {
+            Trail::Amount(amount) => *amount,
+            Trail::Percent(percent) => watermark * percent / 100.0,
+        };
+        if is_buy { watermark + distance } else { watermark - distance }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)] // Derive Eq for comparison
@@ -29,4 +78,132 @@ pub enum OrderType {
     LimitBuy,
     MarketSell,
     LimitSell,
-}
\ No newline at end of file
+    // Held by the matching engine until the price reaches the stop, then released as a market order
+    StopBuy,
+    StopSell,
+    // Released as a limit order at `limit_price` once the stop is reached
+    StopLimitBuy,
+    StopLimitSell,
+    // Stop that follows the price at a fixed distance, released as a market order
+    TrailingStopBuy,
+    TrailingStopSell,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+    Expired,
+    Replaced, // The resting order now has the quantity and price asked for
+    Triggered, // A stop was reached and the order was released to the book
+    CancelRejected, // A cancel or replace could not be applied, the order is unchanged
+}
+
+// Execution report sent back by the matching engine for every change to an order
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrderStatusUpdate {
+    pub order_id: String,
+    pub trader_id: String,
+    pub stock_symbol: String,
+    pub status: OrderStatus,
+    pub fill_quantity: u32, // Quantity traded by this report, 0 if it is not a fill
+    pub fill_price: Option<f64>,
+    pub cumulative_quantity: u32, // Quantity traded so far over the life of the order
+    pub leaves_quantity: u32, // Quantity still open in the book
+    pub timestamp: u64, // Milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>, // Why an order or a request against it was refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liquidity: Option<Liquidity>, // Which side of the trade a fill was on
+    #[serde(default)]
+    pub fees: f64, // Charged on a fill by the trader's broker, negative for a net rebate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reject_code: Option<RejectCode>, // Set when a refusal is about the request rather than the order it names
+}
+
+// Refusals that must not close the order they name
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectCode {
+    DuplicateOrderId, // A new order reused the id of one already sent, which carries on
+}
+
+// A fill's order either rested in the book and made the market or traded against it on arrival
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+// Visible liquidity of one symbol's book, best prices first. Hidden iceberg quantity is left out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookDepth {
+    pub symbol: String,
+    pub timestamp: u64,
+    pub bids: Vec<DepthLevel>,
+    pub asks: Vec<DepthLevel>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DepthLevel {
+    pub price: f64,
+    pub quantity: u32,
+    pub orders: usize,
+}
+
+// Message on the orders queue: a new order, or a change to one resting in the book
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum OrderRequest {
+    New(Order),
+    Cancel {
+        order_id: String,
+        trader_id: String,
+        stock_symbol: String,
+    },
+    // Cancel/replace: the new total quantity, including what has already filled, and limit price
+    Replace {
+        order_id: String,
+        trader_id: String,
+        stock_symbol: String,
+        quantity: u32,
+        limit_price: f64,
+    },
+}
+
+impl OrderRequest {
+    pub fn trader_id(&self) -> &str {
+        match self {
+            OrderRequest::New(order) => &order.trader_id,
+            OrderRequest::Cancel { trader_id, .. } | OrderRequest::Replace { trader_id, .. } => trader_id,
+        }
+    }
+}
+
+impl OrderType {
+    pub fn is_buy(&self) -> bool {
+        matches!(
+            self,
+            OrderType::MarketBuy | OrderType::LimitBuy | OrderType::StopBuy | OrderType::StopLimitBuy | OrderType::TrailingStopBuy
+        )
+    }
+
+    pub fn is_stop(&self) -> bool {
+        self.released_type() != *self
+    }
+
+    // The order type a stop turns into when triggered, other types are already live
+    pub fn released_type(&self) -> OrderType {
+        match self {
+            OrderType::StopBuy | OrderType::TrailingStopBuy => OrderType::MarketBuy,
+            OrderType::StopSell | OrderType::TrailingStopSell => OrderType::MarketSell,
+            OrderType::StopLimitBuy => OrderType::LimitBuy,
+            OrderType::StopLimitSell => OrderType::LimitSell,
+            other => other.clone(),
+        }
+    }
+}
@@ -1,70 +1,91 @@
-use lapin::{
-    options::*, types::FieldTable, BasicProperties, Connection, ConnectionProperties, ExchangeKind,
-};
-use serde::{Deserialize, Serialize};
 use futures::StreamExt; // Import the StreamExt trait
-use crate::models::Order; // Import the Order struct
+use crate::models::{OrderRequest, OrderStatusUpdate, Stock};
+use crate::matching_engine::MatchingEngine;
+use crate::clock::Clock;
+use crate::config::QueueNames;
+use crate::transport::{Transport, TransportError};
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Serialize, Debug, Deserialize)]
-struct OrderStatusUpdate {
-    order_id: String,
-    status: String,
+// Price levels of each side sent on the depth feed
+const DEPTH_LEVELS: usize = 5;
+
+// Publish execution reports, then the visible book of every symbol they changed
+async fn publish_reports(transport: &Arc<dyn Transport>, queues: &QueueNames, engine: &MatchingEngine, reports: Vec<OrderStatusUpdate>) -> Result<(), TransportError> {
+    let mut changed: Vec<String> = Vec::new();
+    for order_status_update in reports {
+        if !changed.contains(&order_status_update.stock_symbol) {
+            changed.push(order_status_update.stock_symbol.clone());
+        }
+        // Serialize the order status update to JSON
+        let serialized_status = serde_json::to_string(&order_status_update)?;
+        // Publish the order status update to the order status queue
+        transport.publish("", &queues.order_status, serialized_status.as_bytes()).await?;
+        //println!("Order Status Sent: {}", serialized_status);
+    }
+    for symbol in changed {
+        let depth = serde_json::to_string(&engine.depth(&symbol, DEPTH_LEVELS))?;
+        transport.publish(&queues.depth, "", depth.as_bytes()).await?;
+    }
+    Ok(())
 }
 
-pub async fn run_order_sender() -> Result<(), Box<dyn std::error::Error>> {
-    // Establish connection to RabbitMQ server
-    let conn = Connection::connect("amqp://127.0.0.1:5672/%2f", ConnectionProperties::default()).await?;
-    let channel = conn.create_channel().await?;
-    // let queue = channel.queue_declare("orders", QueueDeclareOptions::default(), FieldTable::default()).await?;
-    let mut consumer = channel.basic_consume("orders", "", BasicConsumeOptions::default(), FieldTable::default()).await?;
-    channel.exchange_declare(
-        "order_status",
-        ExchangeKind::Direct,
-        ExchangeDeclareOptions::default(),
-        FieldTable::default(),
-    ).await?;
+// `session_close` is when DAY orders expire, in simulated milliseconds since the Unix epoch
+pub async fn run_order_sender(transport: Arc<dyn Transport>, queues: QueueNames, clock: Clock, session_close: u64) -> Result<(), TransportError> {
+    let mut consumer = transport.subscribe("", &queues.orders).await?;
+    // The engine watches the feed to trigger the stop orders it holds
+    let mut prices = transport.subscribe(&queues.stocks, "").await?;
 
     println!("Order Sender: Waiting for orders...");
 
-    // Receive and process orders in a loop
-    while let Some(delivery) = consumer.next().await {
-        let delivery = delivery?;
-        let order_data = String::from_utf8_lossy(&delivery.data);
-        //println!("Received Order: {}", order_data);
+    let mut engine = MatchingEngine::new(clock, session_close);
 
-        // Deserialize the JSON to order data
-        let order: Order = match serde_json::from_str(&order_data) {
-            Ok(order) => order,
-            Err(err) => {
-                println!("Failed to deserialize order: {}", err);
-                continue;  // Skip to the next message if deserialization fails
+    // Receive and process orders in a loop, waking up whenever a working order is due to expire
+    loop {
+        let next_expiry = engine.next_expiry();
+        let until_expiry = Duration::from_millis(next_expiry.unwrap_or(0).saturating_sub(clock.now_millis()));
+        tokio::select! {
+            biased;
+            _ = clock.sleep(until_expiry), if next_expiry.is_some() => {
+                let reports = engine.expire();
+                publish_reports(&transport, &queues, &engine, reports).await?;
             }
-        };
+            delivery = consumer.next() => {
+                let Some(delivery) = delivery else { break };
+                let delivery = delivery?;
+                let order_data = String::from_utf8_lossy(&delivery.data);
+                //println!("Received Order: {}", order_data);
 
-        // Process the order (this can be more complex in a real application)
-        //println!("Processing Order: {:?}", order);
+                // Deserialize the JSON to a new order, cancel or replace
+                let request: OrderRequest = match serde_json::from_str(&order_data) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        println!("Failed to deserialize order: {}", err);
+                        continue;  // Skip to the next message if deserialization fails
+                    }
+                };
 
-        // Create an order status update
-        let order_status_update = OrderStatusUpdate {
-            order_id: order.order_id.clone(),
-            status: "complete".to_string(),
-        };
+                // Apply the request to the book for its symbol
+                let reports = engine.handle(request);
+                publish_reports(&transport, &queues, &engine, reports).await?;
 
-        // Serialize the order status update to JSON
-        let serialized_status = serde_json::to_string(&order_status_update)?;
-        // Publish the order status update to the "order_status" queue
-        channel.basic_publish(
-            "",
-            "order_status",
-            BasicPublishOptions::default(),
-            serialized_status.as_bytes(),
-            BasicProperties::default(),
-        ).await?;
-        //println!("Order Status Sent: {}", serialized_status);
-
-        // Acknowledge the message
-        delivery.ack(BasicAckOptions::default()).await?;
+                // Acknowledge the message
+                delivery.ack().await?;
+            }
+            delivery = prices.next() => {
+                let Some(delivery) = delivery else { break };
+                let delivery = delivery?;
+                match serde_json::from_slice::<Stock>(&delivery.data) {
+                    Ok(stock) => {
+                        let reports = engine.on_price(&stock);
+                        publish_reports(&transport, &queues, &engine, reports).await?;
+                    }
+                    Err(err) => println!("Failed to deserialize stock: {}", err),
+                }
+                delivery.ack().await?;
+            }
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,31 +1,12 @@
-use lapin::{
-    options::*, types::FieldTable, BasicProperties, Connection, ConnectionProperties, ExchangeKind,
-};
-use serde::{Deserialize, Serialize};
 use futures::StreamExt; // Import the StreamExt trait
+use crate::models::OrderStatusUpdate;
+use crate::config::QueueNames;
+use crate::transport::{Transport, TransportError};
+use std::sync::Arc;
 
-#[derive(Serialize, Debug, Deserialize)]
-struct OrderStatusUpdate {
-    order_id: String,
-    status: String,
-}
-
-pub async fn run_order_status_receiver() -> Result<(), Box<dyn std::error::Error>> {
-    // Establish connection to RabbitMQ server for receiving order status updates
-    let conn = Connection::connect("amqp://127.0.0.1:5672/%2f", ConnectionProperties::default()).await?;
-    let channel = conn.create_channel().await?;
-    //let queue = channel.queue_declare("order_status", QueueDeclareOptions::default(), FieldTable::default()).await?;
-    let mut consumer = channel.basic_consume("order_status", "", BasicConsumeOptions::default(), FieldTable::default()).await?;
-
-    // Establish connection to RabbitMQ server for sending processed status updates
-    let send_conn = Connection::connect("amqp://127.0.0.1:5672/%2f", ConnectionProperties::default()).await?;
-    let send_channel = send_conn.create_channel().await?;
-    send_channel.exchange_declare(
-        "processed_order_status",
-        ExchangeKind::Direct,
-        ExchangeDeclareOptions::default(),
-        FieldTable::default(),
-    ).await?;
+pub async fn run_order_status_receiver(transport: Arc<dyn Transport>, queues: QueueNames) -> Result<(), TransportError> {
+    // Receive order status updates from the matching engine
+    let mut consumer = transport.subscribe("", &queues.order_status).await?;
 
     //println!("Order Status Listener: Waiting for order status updates...");
 
@@ -51,18 +32,12 @@ pub async fn run_order_status_receiver() -> Result<(), Box<dyn std::error::Error
         // Serialize the processed order status update to JSON
         let serialized_status = serde_json::to_string(&status_update)?;
 
-        // Publish the processed order status update to the "processed_order_status" queue
-        send_channel.basic_publish(
-            "",
-            "processed_order_status",
-            BasicPublishOptions::default(),
-            serialized_status.as_bytes(),
-            BasicProperties::default(),
-        ).await?;
+        // Publish the processed order status update to every broker through the processed order status exchange
+        transport.publish(&queues.processed_order_status, "", serialized_status.as_bytes()).await?;
         //println!("Processed Order Status Sent: {}", serialized_status);
 
         // Acknowledge the message
-        delivery.ack(BasicAckOptions::default()).await?;
+        delivery.ack().await?;
     }
 
     Ok(())
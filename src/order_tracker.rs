@@ -0,0 +1,422 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use crate::models::{Order, OrderStatus, OrderStatusUpdate};
+
+// Where an order is in its life as the trader sees it.
+// PendingNew until the exchange first reports on it, then New and PartiallyFilled while it can still trade.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderState {
+    Held, // Exit of a bracket waiting for its entry, not sent yet
+    PendingNew,
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+    Expired,
+}
+
+impl OrderState {
+    pub fn is_terminal(self) -> bool {
+        matches!(self, OrderState::Filled | OrderState::Cancelled | OrderState::Rejected | OrderState::Expired)
+    }
+
+    fn can_become(self, next: OrderState) -> bool {
+        use OrderState::*;
+        matches!(
+            (self, next),
+            (Held, PendingNew | Cancelled | Rejected)
+                | (PendingNew, New | PartiallyFilled | Filled | Cancelled | Rejected | Expired)
+                | (New, PartiallyFilled | Filled | Cancelled | Expired)
+                | (PartiallyFilled, PartiallyFilled | Filled | Cancelled | Expired)
+        )
+    }
+}
+
+// State an execution report moves the order to, none for the outcome of a cancel or replace request
+fn next_state(status: OrderStatus) -> Option<OrderState> {
+    match status {
+        OrderStatus::New => Some(OrderState::New),
+        OrderStatus::PartiallyFilled => Some(OrderState::PartiallyFilled),
+        OrderStatus::Filled => Some(OrderState::Filled),
+        OrderStatus::Cancelled => Some(OrderState::Cancelled),
+        OrderStatus::Rejected => Some(OrderState::Rejected),
+        OrderStatus::Expired => Some(OrderState::Expired),
+        OrderStatus::Replaced | OrderStatus::CancelRejected | OrderStatus::Triggered => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackedOrder {
+    pub order: Order,
+    pub state: OrderState,
+    pub cumulative_quantity: u32,
+    pub leaves_quantity: u32,
+    pub reserved_cash: f64, // Cash still held for the unfilled quantity, zero for sells
+    pub pending_cancel: bool, // A cancel was sent and not answered yet
+    pub pending_replace: Option<(u32, f64)>, // Quantity and limit price of an unanswered replace
+    pub group: Option<String>, // Id of the order group it belongs to
+}
+
+impl TrackedOrder {
+    pub fn is_open(&self) -> bool {
+        !self.state.is_terminal()
+    }
+
+    pub fn has_pending_request(&self) -> bool {
+        self.pending_cancel || self.pending_replace.is_some()
+    }
+}
+
+// Orders managed together. The legs are one-cancels-other: the first to trade or close cancels the rest.
+// A bracket also has an entry, its legs are held until the entry is done trading.
+#[derive(Debug, Clone)]
+pub struct OrderGroup {
+    pub parent: Option<String>, // Entry of a bracket
+    pub legs: Vec<String>,
+}
+
+// What an execution report did to an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Applied,
+    Duplicate, // Already seen, nothing changed
+}
+
+// Every order a trader has sent this session, looked up by order id.
+// Finished orders are kept so late duplicates of their reports are recognised,
+// the open ones are indexed apart so walking them does not grow with the session.
+#[derive(Debug, Clone, Default)]
+pub struct OrderTracker {
+    orders: HashMap<String, TrackedOrder>,
+    open: BTreeSet<(u64, String)>, // Orders that can still trade, by sequence so in the order they were sent
+    groups: HashMap<String, OrderGroup>, // Keyed by the id of the entry, or of the first leg without one
+}
+
+impl OrderTracker {
+    pub fn insert(&mut self, order: Order, reserved_cash: f64) -> Result<(), String> {
+        if self.orders.contains_key(&order.order_id) {
+            return Err(format!("Order {} is already tracked", order.order_id));
+        }
+        let tracked = TrackedOrder {
+            state: OrderState::PendingNew,
+            cumulative_quantity: 0,
+            leaves_quantity: order.quantity,
+            reserved_cash,
+            pending_cancel: false,
+            pending_replace: None,
+            group: None,
+            order,
+        };
+        self.open.insert((order_sequence(&tracked.order.order_id), tracked.order.order_id.clone()));
+        self.orders.insert(tracked.order.order_id.clone(), tracked);
+        Ok(())
+    }
+
+    // Track an order that is only sent once `activate` is called
+    pub fn insert_held(&mut self, order: Order) -> Result<(), String> {
+        let order_id = order.order_id.clone();
+        self.insert(order, 0.0)?;
+        if let Some(tracked) = self.orders.get_mut(&order_id) {
+            tracked.state = OrderState::Held;
+        }
+        Ok(())
+    }
+
+    // Tie already tracked orders into a group
+    pub fn link(&mut self, group: OrderGroup) {
+        let Some(id) = group.parent.as_ref().or(group.legs.first()).cloned() else { return };
+        for order_id in group.parent.iter().chain(&group.legs) {
+            if let Some(tracked) = self.orders.get_mut(order_id) {
+                tracked.group = Some(id.clone());
+            }
+        }
+        self.groups.insert(id, group);
+    }
+
+    pub fn group_of(&self, order_id: &str) -> Option<&OrderGroup> {
+        self.orders.get(order_id)?.group.as_ref().and_then(|id| self.groups.get(id))
+    }
+
+    // Release a held order for `quantity` shares so it can be sent
+    pub fn activate(&mut self, order_id: &str, quantity: u32) -> Option<&Order> {
+        let tracked = self.orders.get_mut(order_id).filter(|t| t.state == OrderState::Held)?;
+        tracked.state = OrderState::PendingNew;
+        tracked.order.quantity = quantity;
+        tracked.leaves_quantity = quantity;
+        Some(&tracked.order)
+    }
+
+    pub fn get(&self, order_id: &str) -> Option<&TrackedOrder> {
+        self.orders.get(order_id)
+    }
+
+    pub fn get_mut(&mut self, order_id: &str) -> Option<&mut TrackedOrder> {
+        self.orders.get_mut(order_id)
+    }
+
+    // Drop an order that never reached the exchange
+    pub fn remove(&mut self, order_id: &str) -> Option<TrackedOrder> {
+        self.unindex(order_id);
+        self.orders.remove(order_id)
+    }
+
+    // Take an order that can no longer trade out of the open index
+    fn unindex(&mut self, order_id: &str) {
+        self.open.remove(&(order_sequence(order_id), order_id.to_string()));
+    }
+
+    // Orders that can still trade, in the order they were sent
+    pub fn open_orders(&self) -> impl Iterator<Item = &TrackedOrder> {
+        self.open.iter().filter_map(|(_, order_id)| self.orders.get(order_id))
+    }
+
+    // Shares of a symbol open sell orders may still sell. The legs of a group sell the same
+    // shares, so a group counts once for what its legs have not sold between them.
+    pub fn promised_quantity(&self, symbol: &str) -> u32 {
+        let mut promised = 0;
+        let mut groups = HashSet::new();
+        let selling = self.open_orders()
+            .filter(|o| o.order.stock_symbol == symbol && !o.order.order_type.is_buy() && o.state != OrderState::Held);
+        for tracked in selling {
+            match tracked.group.as_ref().and_then(|id| self.groups.get(id).map(|group| (id, group))) {
+                Some((id, group)) if groups.insert(id) => {
+                    let legs: Vec<&TrackedOrder> = group.legs.iter().filter_map(|leg| self.orders.get(leg)).collect();
+                    let open = legs.iter().filter(|leg| leg.is_open() && leg.state != OrderState::Held).map(|leg| leg.leaves_quantity).max().unwrap_or(0);
+                    let quantity = legs.iter().map(|leg| leg.order.quantity).max().unwrap_or(0);
+                    let sold: u32 = legs.iter().map(|leg| leg.cumulative_quantity).sum();
+                    promised += open.min(quantity.saturating_sub(sold));
+                }
+                Some(_) => {}
+                None => promised += tracked.leaves_quantity,
+            }
+        }
+        promised
+    }
+
+    // Check an execution report against the order's state and apply it.
+    // Reports that repeat what is already known are reported as duplicates, anything
+    // that would move the order backwards or lose quantity is an error and changes nothing.
+    pub fn apply(&mut self, update: &OrderStatusUpdate) -> Result<Transition, String> {
+        let tracked = self.orders.get_mut(&update.order_id)
+            .ok_or_else(|| format!("Unknown order {}", update.order_id))?;
+        if update.status == OrderStatus::Triggered {
+            // A stop is released once, from then on it trades as the order type it turned into
+            if tracked.state.is_terminal() || !tracked.order.order_type.is_stop() {
+                return Ok(Transition::Duplicate);
+            }
+            tracked.order.order_type = tracked.order.order_type.released_type();
+            return Ok(Transition::Applied);
+        }
+        let Some(next) = next_state(update.status) else {
+            return Ok(Self::apply_request_outcome(tracked, update));
+        };
+
+        let is_fill = matches!(next, OrderState::PartiallyFilled | OrderState::Filled);
+        if tracked.state.is_terminal()
+            || (next == tracked.state && !is_fill)
+            || (is_fill && update.cumulative_quantity <= tracked.cumulative_quantity)
+        {
+            return Ok(Transition::Duplicate);
+        }
+        if !tracked.state.can_become(next) {
+            return Err(format!("Order {} cannot go from {:?} to {:?}", update.order_id, tracked.state, next));
+        }
+        if is_fill && tracked.cumulative_quantity + update.fill_quantity != update.cumulative_quantity {
+            return Err(format!(
+                "Order {} fill of {} takes it from {} to {} filled, a report is missing",
+                update.order_id, update.fill_quantity, tracked.cumulative_quantity, update.cumulative_quantity
+            ));
+        }
+        if update.cumulative_quantity + update.leaves_quantity > tracked.order.quantity {
+            return Err(format!("Order {} reports more than its {} shares", update.order_id, tracked.order.quantity));
+        }
+
+        tracked.state = next;
+        tracked.cumulative_quantity = update.cumulative_quantity;
+        tracked.leaves_quantity = if next.is_terminal() { 0 } else { update.leaves_quantity };
+        if next.is_terminal() {
+            self.unindex(&update.order_id);
+        }
+        Ok(Transition::Applied)
+    }
+
+    // Whether a fill report adds shares the order has not booked yet, as `apply` would find it
+    pub fn is_new_fill(&self, update: &OrderStatusUpdate) -> bool {
+        update.fill_quantity > 0 && self.orders.get(&update.order_id)
+            .is_some_and(|tracked| !tracked.state.is_terminal() && update.cumulative_quantity > tracked.cumulative_quantity)
+    }
+
+    // A replace is applied once acknowledged; a refusal leaves the order as it was. Either answers
+    // every request in flight, so an answer with nothing outstanding is a duplicate.
+    fn apply_request_outcome(tracked: &mut TrackedOrder, update: &OrderStatusUpdate) -> Transition {
+        if tracked.state.is_terminal() || !tracked.has_pending_request() {
+            return Transition::Duplicate;
+        }
+        if update.status == OrderStatus::Replaced {
+            let Some((quantity, limit_price)) = tracked.pending_replace.take() else { return Transition::Duplicate };
+            tracked.order.quantity = quantity;
+            tracked.order.limit_price = Some(limit_price);
+            tracked.leaves_quantity = update.leaves_quantity;
+        } else {
+            tracked.pending_cancel = false;
+            tracked.pending_replace = None;
+        }
+        Transition::Applied
+    }
+
+    // Close an open order without an exchange report, at session end
+    pub fn close(&mut self, order_id: &str, state: OrderState) -> Result<(), String> {
+        let tracked = self.orders.get_mut(order_id).ok_or_else(|| format!("Unknown order {}", order_id))?;
+        if !tracked.state.can_become(state) || !state.is_terminal() {
+            return Err(format!("Order {} cannot go from {:?} to {:?}", order_id, tracked.state, state));
+        }
+        tracked.state = state;
+        tracked.leaves_quantity = 0;
+        self.unindex(order_id);
+        Ok(())
+    }
+}
+
+// Order ids end in the trader's running counter
+fn order_sequence(order_id: &str) -> u64 {
+    order_id.rsplit('-').next().and_then(|n| n.parse().ok()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{OrderType, TimeInForce};
+
+    fn order(order_id: &str, order_type: OrderType, quantity: u32) -> Order {
+        Order {
+            order_id: order_id.to_string(),
+            trader_id: "T".to_string(),
+            stock_symbol: "AAPL".to_string(),
+            order_type,
+            quantity,
+            limit_price: Some(10.0),
+            stop_price: None,
+            trail: None,
+            time_in_force: TimeInForce::Day,
+            display_quantity: None,
+        }
+    }
+
+    fn report(order_id: &str, status: OrderStatus, fill_quantity: u32, cumulative_quantity: u32, leaves_quantity: u32) -> OrderStatusUpdate {
+        OrderStatusUpdate {
+            order_id: order_id.to_string(),
+            trader_id: "T".to_string(),
+            stock_symbol: "AAPL".to_string(),
+            status,
+            fill_quantity,
+            fill_price: (fill_quantity > 0).then_some(10.0),
+            cumulative_quantity,
+            leaves_quantity,
+            timestamp: 0,
+            reason: None,
+            liquidity: None,
+            fees: 0.0,
+            reject_code: None,
+        }
+    }
+
+    fn state(tracker: &OrderTracker, order_id: &str) -> OrderState {
+        tracker.get(order_id).unwrap().state
+    }
+
+    #[test]
+    fn reports_move_an_order_forward_through_its_life() {
+        let mut tracker = OrderTracker::default();
+        tracker.insert(order("T-1", OrderType::LimitBuy, 10), 100.0).unwrap();
+        assert_eq!(state(&tracker, "T-1"), OrderState::PendingNew);
+
+        assert_eq!(tracker.apply(&report("T-1", OrderStatus::New, 0, 0, 10)), Ok(Transition::Applied));
+        assert_eq!(tracker.apply(&report("T-1", OrderStatus::PartiallyFilled, 4, 4, 6)), Ok(Transition::Applied));
+        assert_eq!(tracker.apply(&report("T-1", OrderStatus::PartiallyFilled, 2, 6, 4)), Ok(Transition::Applied));
+        assert_eq!(tracker.apply(&report("T-1", OrderStatus::Filled, 4, 10, 0)), Ok(Transition::Applied));
+        let tracked = tracker.get("T-1").unwrap();
+        assert_eq!((tracked.state, tracked.cumulative_quantity, tracked.leaves_quantity), (OrderState::Filled, 10, 0));
+    }
+
+    #[test]
+    fn reports_that_would_move_an_order_backwards_are_refused() {
+        let mut tracker = OrderTracker::default();
+        tracker.insert(order("T-1", OrderType::LimitBuy, 10), 100.0).unwrap();
+        tracker.apply(&report("T-1", OrderStatus::PartiallyFilled, 4, 4, 6)).unwrap();
+        assert!(tracker.apply(&report("T-1", OrderStatus::New, 0, 0, 10)).is_err());
+        assert!(tracker.apply(&report("T-1", OrderStatus::Rejected, 0, 4, 6)).is_err());
+        assert!(tracker.apply(&report("T-1", OrderStatus::PartiallyFilled, 8, 12, 0)).is_err());
+        assert_eq!(state(&tracker, "T-1"), OrderState::PartiallyFilled);
+
+        // A held exit is only cancelled or sent, it cannot trade before then
+        tracker.insert_held(order("T-2", OrderType::LimitSell, 5)).unwrap();
+        assert!(tracker.apply(&report("T-2", OrderStatus::New, 0, 0, 5)).is_err());
+        assert!(tracker.close("T-2", OrderState::New).is_err());
+        assert_eq!(tracker.close("T-2", OrderState::Cancelled), Ok(()));
+
+        assert!(tracker.apply(&report("T-9", OrderStatus::New, 0, 0, 5)).is_err());
+        assert!(tracker.insert(order("T-1", OrderType::LimitBuy, 3), 30.0).is_err());
+    }
+
+    #[test]
+    fn repeated_and_late_reports_are_duplicates() {
+        let mut tracker = OrderTracker::default();
+        tracker.insert(order("T-1", OrderType::LimitBuy, 10), 100.0).unwrap();
+        let fill = report("T-1", OrderStatus::PartiallyFilled, 4, 4, 6);
+        assert_eq!(tracker.apply(&fill), Ok(Transition::Applied));
+        assert!(!tracker.is_new_fill(&fill));
+        assert_eq!(tracker.apply(&fill), Ok(Transition::Duplicate));
+
+        tracker.apply(&report("T-1", OrderStatus::Cancelled, 0, 4, 6)).unwrap();
+        assert_eq!(tracker.apply(&report("T-1", OrderStatus::Cancelled, 0, 4, 6)), Ok(Transition::Duplicate));
+        assert_eq!(tracker.apply(&report("T-1", OrderStatus::Filled, 6, 10, 0)), Ok(Transition::Duplicate));
+        assert_eq!(tracker.get("T-1").unwrap().cumulative_quantity, 4);
+    }
+
+    #[test]
+    fn a_fill_after_a_missing_report_is_refused() {
+        let mut tracker = OrderTracker::default();
+        tracker.insert(order("T-1", OrderType::LimitBuy, 10), 100.0).unwrap();
+        tracker.apply(&report("T-1", OrderStatus::PartiallyFilled, 2, 2, 8)).unwrap();
+        // The fill taking it from 2 to 5 never arrived
+        let error = tracker.apply(&report("T-1", OrderStatus::PartiallyFilled, 3, 8, 2)).unwrap_err();
+        assert!(error.contains("a report is missing"), "{}", error);
+        let tracked = tracker.get("T-1").unwrap();
+        assert_eq!((tracked.cumulative_quantity, tracked.leaves_quantity), (2, 8));
+    }
+
+    #[test]
+    fn open_orders_stay_in_the_order_they_were_sent() {
+        let mut tracker = OrderTracker::default();
+        for id in ["T-3", "T-10", "T-2", "T-7"] {
+            tracker.insert(order(id, OrderType::LimitBuy, 5), 50.0).unwrap();
+        }
+        tracker.apply(&report("T-3", OrderStatus::Filled, 5, 5, 0)).unwrap();
+        tracker.apply(&report("T-10", OrderStatus::New, 0, 0, 5)).unwrap();
+        tracker.close("T-7", OrderState::Cancelled).unwrap();
+        let open: Vec<&str> = tracker.open_orders().map(|o| o.order.order_id.as_str()).collect();
+        assert_eq!(open, ["T-2", "T-10"]);
+
+        tracker.apply(&report("T-2", OrderStatus::Expired, 0, 0, 5)).unwrap();
+        tracker.remove("T-10");
+        assert_eq!(tracker.open_orders().count(), 0);
+    }
+
+    #[test]
+    fn the_legs_of_a_group_promise_the_same_shares_once() {
+        let mut tracker = OrderTracker::default();
+        tracker.insert(order("T-1", OrderType::LimitSell, 4), 0.0).unwrap();
+        tracker.insert(order("T-2", OrderType::LimitBuy, 9), 90.0).unwrap();
+        tracker.insert(order("T-3", OrderType::LimitSell, 10), 0.0).unwrap();
+        tracker.insert(order("T-4", OrderType::StopSell, 10), 0.0).unwrap();
+        tracker.link(OrderGroup { parent: None, legs: vec!["T-3".to_string(), "T-4".to_string()] });
+        assert_eq!(tracker.promised_quantity("AAPL"), 14);
+
+        // Shares one leg sold are no longer promised by either
+        tracker.apply(&report("T-3", OrderStatus::PartiallyFilled, 6, 6, 4)).unwrap();
+        assert_eq!(tracker.promised_quantity("AAPL"), 8);
+        tracker.apply(&report("T-1", OrderStatus::Filled, 4, 4, 0)).unwrap();
+        assert_eq!(tracker.promised_quantity("AAPL"), 4);
+        assert_eq!(tracker.promised_quantity("MSFT"), 0);
+    }
+}
@@ -1,41 +1,74 @@
+use std::collections::HashMap;
+use crate::analytics::{display_leaderboard, PerformanceReport};
+use crate::config::CostBasis;
+use crate::ledger::{symbol_breakdown, Fill, Side, SymbolPnl};
 use crate::traders::Trader;
 use crate::models::Order;
 use crate::stock_listener::StockStore;
 use crate::color::print_colored;
+use serde::Serialize;
 
+#[derive(Debug, Serialize)]
+pub struct HeldStock {
+    pub symbol: String,
+    pub latest_price: f64,
+    pub average_cost: f64,
+    pub quantity: i64, // Negative when short
+}
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Portfolio {
     pub trader_id: String,
     pub cash_left: f64,
-    pub held_stocks: Vec<(String, f64, f64, u32)>, // (stock symbol, latest price, average cost price, quantity)
+    pub held_stocks: Vec<HeldStock>,
     pub total_amount: f64,
     pub profit_loss: f64,
+    pub realized_pnl: f64,
+    pub unrealized_pnl: f64,
+    pub fees: f64,
+    pub borrow_fees: f64, // Paid for shares sold short
+    pub turnover: f64,
+    pub cost_basis: CostBasis,
+    pub symbols: Vec<SymbolPnl>, // Profit broken out per symbol traded or held
+    pub ledger: Vec<Fill>,
     pub pending_orders: Vec<Order>, // Add pending orders
 }
 
 impl Portfolio {
-    pub async fn new(trader: &Trader, stock_store: &StockStore) -> Self {
+    pub async fn new(trader: &Trader, stock_store: &StockStore, cost_basis: CostBasis) -> Self {
         let mut held_stocks = Vec::new();
-        let mut total_stock_value = 0.0;
 
         let store = stock_store.read().await;
 
         for stock in &trader.portfolio {
             let latest_price = store.get(&stock.symbol).map(|s| s.price).unwrap_or(stock.average_cost);
-            held_stocks.push((stock.symbol.clone(), latest_price, stock.average_cost, stock.quantity));
-            total_stock_value += latest_price * stock.quantity as f64;
+            held_stocks.push(HeldStock {
+                symbol: stock.symbol.clone(),
+                latest_price,
+                average_cost: stock.average_cost,
+                quantity: stock.quantity,
+            });
         }
 
-        let total_amount = trader.cash + total_stock_value;
+        let total_amount = trader.equity(&store);
+        let latest_prices: HashMap<String, f64> = store.iter().map(|(symbol, stock)| (symbol.clone(), stock.price)).collect();
+        let symbols = symbol_breakdown(&trader.opening_positions, &trader.ledger, cost_basis, &latest_prices);
 
         Self {
             trader_id: trader.id.clone(),
             cash_left: trader.cash,
             held_stocks,
             total_amount,
-            profit_loss: total_amount - 5000.0,
-            pending_orders: trader.pending_orders.clone(), // Include pending orders
+            profit_loss: total_amount - trader.initial_value,
+            realized_pnl: symbols.iter().map(|s| s.realized_pnl).sum(),
+            unrealized_pnl: symbols.iter().map(|s| s.unrealized_pnl).sum(),
+            fees: symbols.iter().map(|s| s.fees).sum(),
+            borrow_fees: trader.borrow_fees,
+            turnover: symbols.iter().map(|s| s.turnover).sum(),
+            cost_basis,
+            symbols,
+            ledger: trader.ledger.clone(),
+            pending_orders: trader.pending_orders().cloned().collect(), // Include pending orders
         }
     }
 
@@ -49,7 +82,7 @@ impl Portfolio {
             print_colored(
                 &format!(
                     "  Symbol: {}, Latest Price: ${:.2}, Average Cost per stock: ${:.2}, Quantity: {}",
-                    stock.0, stock.1, stock.2, stock.3
+                    stock.symbol, stock.latest_price, stock.average_cost, stock.quantity
                 ),
                 "cyan"
             );
@@ -58,15 +91,58 @@ impl Portfolio {
     
         let profit_loss_color = if self.profit_loss >= 0.0 { "green" } else { "red" };
         print_colored(&format!("Profit/Loss: ${:.2}", self.profit_loss), profit_loss_color);
+        print_colored(
+            &format!(
+                "  Realized ({:?}): ${:.2}, Unrealized: ${:.2}, Turnover: ${:.2}",
+                self.cost_basis, self.realized_pnl, self.unrealized_pnl, self.turnover
+            ),
+            profit_loss_color
+        );
+        // Realized and unrealized profit are before costs, the profit/loss above is after them
+        print_colored(&format!("  Fees: ${:.2}, Borrow Fees: ${:.2}", self.fees, self.borrow_fees), "yellow");
+        for pnl in &self.symbols {
+            print_colored(
+                &format!(
+                    "  {}: Realized ${:.2}, Unrealized ${:.2}, Fees ${:.2}, Turnover ${:.2}, Round Trips: {}",
+                    pnl.symbol, pnl.realized_pnl, pnl.unrealized_pnl, pnl.fees, pnl.turnover, pnl.round_trips
+                ),
+                "cyan"
+            );
+        }
     
+        print_colored("Trade Ledger:", "yellow");
+        for fill in &self.ledger {
+            print_colored(
+                &format!(
+                    "  [{}] {} {:?} {} {} @ ${:.2}, Fees ${:.2}",
+                    fill.timestamp, fill.order_id, fill.side, fill.quantity, fill.symbol, fill.price, fill.fees
+                ),
+                if fill.side == Side::Buy { "green" } else { "red" }
+            );
+        }
+
         print_colored(&format!("Pending Orders: {:?}", self.pending_orders), "yellow");
     }
 }
 
-pub async fn display_all_portfolios(traders: &[Trader], stock_store: &StockStore) {
+// Print every trader's portfolio and performance, returning them for the session report
+pub async fn display_all_portfolios(
+    traders: &[Trader],
+    stock_store: &StockStore,
+    cost_basis: CostBasis,
+    periods_per_year: f64,
+) -> (Vec<Portfolio>, Vec<PerformanceReport>) {
+    let mut portfolios = Vec::new();
+    let mut reports = Vec::new();
     for trader in traders {
-        let portfolio = Portfolio::new(trader, stock_store).await;
+        let portfolio = Portfolio::new(trader, stock_store, cost_basis).await;
         portfolio.display();
+        let report = PerformanceReport::new(trader, &portfolio, periods_per_year);
+        report.display();
+        portfolios.push(portfolio);
+        reports.push(report);
         println!("-----------------------------");
     }
+    display_leaderboard(&reports);
+    (portfolios, reports)
 }
\ No newline at end of file
@@ -0,0 +1,94 @@
+use rand::{Rng, RngCore};
+use crate::config::PriceModelConfig;
+
+// Evolves a price over one time step of `dt` years
+pub trait PriceModel: Send {
+    fn next_price(&mut self, price: f64, dt: f64, rng: &mut dyn RngCore) -> f64;
+}
+
+// Standard normal sample using the Box-Muller transform
+fn standard_normal(rng: &mut dyn RngCore) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>(); // (0, 1] so the log is finite
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+// Poisson sample using Knuth's method, fine for the small means of a single time step
+fn poisson(rng: &mut dyn RngCore, mean: f64) -> u32 {
+    let limit = (-mean).exp();
+    let mut count = 0;
+    let mut product: f64 = rng.gen();
+    while product > limit {
+        count += 1;
+        product *= rng.gen::<f64>();
+    }
+    count
+}
+
+// dS = mu S dt + sigma S dW, prices stay positive
+pub struct GeometricBrownianMotion {
+    pub drift: f64,
+    pub volatility: f64,
+}
+
+impl PriceModel for GeometricBrownianMotion {
+    fn next_price(&mut self, price: f64, dt: f64, rng: &mut dyn RngCore) -> f64 {
+        let diffusion = self.volatility * dt.sqrt() * standard_normal(rng);
+        price * ((self.drift - 0.5 * self.volatility.powi(2)) * dt + diffusion).exp()
+    }
+}
+
+// Merton jump-diffusion: GBM plus Poisson arrivals of log-normally distributed jumps.
+// The drift is compensated so `drift` stays the expected return.
+pub struct JumpDiffusion {
+    pub drift: f64,
+    pub volatility: f64,
+    pub jump_intensity: f64, // Expected number of jumps per year
+    pub jump_mean: f64, // Mean of the log jump size
+    pub jump_volatility: f64, // Standard deviation of the log jump size
+}
+
+impl PriceModel for JumpDiffusion {
+    fn next_price(&mut self, price: f64, dt: f64, rng: &mut dyn RngCore) -> f64 {
+        let compensator = self.jump_intensity * ((self.jump_mean + 0.5 * self.jump_volatility.powi(2)).exp() - 1.0);
+        let diffusion = self.volatility * dt.sqrt() * standard_normal(rng);
+        let jumps = poisson(rng, self.jump_intensity * dt);
+        let jump_size = (0..jumps).map(|_| self.jump_mean + self.jump_volatility * standard_normal(rng)).sum::<f64>();
+        price * ((self.drift - 0.5 * self.volatility.powi(2) - compensator) * dt + diffusion + jump_size).exp()
+    }
+}
+
+// Ornstein-Uhlenbeck process on the log price, pulled back towards `mean` at rate `speed`.
+// Uses the exact discretisation so large steps do not overshoot.
+pub struct MeanReversion {
+    pub speed: f64,
+    pub mean: f64,
+    pub volatility: f64,
+}
+
+impl PriceModel for MeanReversion {
+    fn next_price(&mut self, price: f64, dt: f64, rng: &mut dyn RngCore) -> f64 {
+        let log_mean = self.mean.ln();
+        let decay = (-self.speed * dt).exp();
+        let deviation = if self.speed > 0.0 {
+            self.volatility * ((1.0 - decay * decay) / (2.0 * self.speed)).sqrt()
+        } else {
+            self.volatility * dt.sqrt()
+        };
+        (log_mean + (price.ln() - log_mean) * decay + deviation * standard_normal(rng)).exp()
+    }
+}
+
+pub fn build_price_model(config: &PriceModelConfig, start_price: f64) -> Box<dyn PriceModel> {
+    match *config {
+        PriceModelConfig::Gbm { drift, volatility } => Box::new(GeometricBrownianMotion { drift, volatility }),
+        PriceModelConfig::JumpDiffusion { drift, volatility, jump_intensity, jump_mean, jump_volatility } => {
+            Box::new(JumpDiffusion { drift, volatility, jump_intensity, jump_mean, jump_volatility })
+        }
+        PriceModelConfig::MeanReversion { speed, mean, volatility } => Box::new(MeanReversion {
+            speed,
+            mean: mean.unwrap_or(start_price),
+            volatility,
+        }),
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::clock::Clock;
+use crate::config::QueueNames;
+use crate::models::{PriceChange, Stock};
+use crate::transport::{Transport, TransportError};
+
+// One historical observation of a symbol's price
+#[derive(Debug, Clone)]
+struct Tick {
+    symbol: String,
+    timestamp: u64, // Milliseconds since the Unix epoch
+    price: f64,
+}
+
+// Column positions of the fields the replay needs
+struct Columns {
+    symbol: usize,
+    timestamp: usize,
+    price: usize,
+}
+
+impl Columns {
+    // Named columns from a header row, the price is taken from `price` or else `close`
+    fn from_header(header: &[&str]) -> Option<Self> {
+        let find = |name: &str| header.iter().position(|column| column.eq_ignore_ascii_case(name));
+        Some(Self {
+            symbol: find("symbol")?,
+            timestamp: find("timestamp")?,
+            price: find("price").or_else(|| find("close"))?,
+        })
+    }
+
+    // Without a header: ticks are symbol,timestamp,price,volume and bars are symbol,timestamp,open,high,low,close,volume
+    fn from_width(width: usize) -> Option<Self> {
+        match width {
+            4 => Some(Self { symbol: 0, timestamp: 1, price: 2 }),
+            7 => Some(Self { symbol: 0, timestamp: 1, price: 5 }),
+            _ => None,
+        }
+    }
+}
+
+fn load_ticks(path: &str) -> Result<Vec<Tick>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let mut lines = contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()).peekable();
+
+    let columns = match lines.peek() {
+        Some((_, first)) => {
+            let fields: Vec<&str> = first.split(',').map(str::trim).collect();
+            if fields.iter().any(|field| field.eq_ignore_ascii_case("symbol")) {
+                lines.next();
+                Columns::from_header(&fields).ok_or_else(|| format!("{}: header needs symbol, timestamp and price or close columns", path))?
+            } else {
+                Columns::from_width(fields.len()).ok_or_else(|| format!("{}: expected 4 tick or 7 bar columns, found {}", path, fields.len()))?
+            }
+        }
+        None => return Ok(Vec::new()),
+    };
+
+    lines.map(|(number, line)| {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let field = |index: usize| fields.get(index).copied().ok_or_else(|| format!("{}:{}: missing column {}", path, number + 1, index + 1));
+        Ok(Tick {
+            symbol: field(columns.symbol)?.to_string(),
+            timestamp: field(columns.timestamp)?.parse().map_err(|e| format!("{}:{}: bad timestamp: {}", path, number + 1, e))?,
+            price: field(columns.price)?.parse::<f64>().map_err(|e| format!("{}:{}: bad price: {}", path, number + 1, e))
+                .and_then(|price| match price.is_finite() && price > 0.0 {
+                    true => Ok(price),
+                    false => Err(format!("{}:{}: bad price: {} is not a positive number", path, number + 1, price)),
+                })?,
+        })
+    }).collect()
+}
+
+// Publish historical ticks as stock updates, spaced by their original inter-arrival times on the simulation clock
+pub async fn run_replay(
+    transport: Arc<dyn Transport>,
+    queues: QueueNames,
+    files: Vec<String>,
+    clock: Clock,
+) -> Result<(), TransportError> {
+    let mut ticks = Vec::new();
+    for file in &files {
+        ticks.extend(load_ticks(file)?);
+    }
+    // Stable sort keeps the file order for ticks sharing a timestamp
+    ticks.sort_by_key(|tick| tick.timestamp);
+    println!("Replaying {} ticks from {} file(s)", ticks.len(), files.len());
+
+    let mut last_prices: HashMap<String, f64> = HashMap::new();
+    let mut last_timestamp = ticks.first().map(|tick| tick.timestamp);
+    for tick in ticks {
+        if let Some(previous) = last_timestamp {
+            if tick.timestamp > previous {
+                clock.sleep(Duration::from_millis(tick.timestamp - previous)).await;
+            }
+        }
+        last_timestamp = Some(tick.timestamp);
+
+        // The first tick of a symbol has nothing to compare against
+        let previous_price = last_prices.insert(tick.symbol.clone(), tick.price).unwrap_or(tick.price);
+        let change = tick.price - previous_price;
+        let stock = Stock {
+            symbol: tick.symbol,
+            price: tick.price,
+            price_change: PriceChange {
+                percentage: (change / previous_price) * 100.0,
+                absolute: change,
+            },
+        };
+
+        let payload = serde_json::to_vec(&stock)?;
+        transport.publish(&queues.stocks, "", &payload).await?;
+    }
+
+    println!("Replay finished");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, contents: &str) -> Result<Vec<Tick>, String> {
+        let path = std::env::temp_dir().join(format!("rust_asm_replay_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let ticks = load_ticks(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        ticks
+    }
+
+    #[test]
+    fn ticks_load_from_a_header_file() {
+        let ticks = load("good", "symbol,timestamp,price,volume\nAAPL,1000,150.25,10\n\nMSFT,1001,300,5\n").unwrap();
+        assert_eq!(ticks.len(), 2);
+        assert_eq!((ticks[1].symbol.as_str(), ticks[1].timestamp, ticks[1].price), ("MSFT", 1001, 300.0));
+    }
+
+    #[test]
+    fn prices_that_are_not_positive_numbers_are_refused_with_their_line() {
+        for (name, price) in [("zero", "0"), ("negative", "-1.5"), ("nan", "NaN"), ("infinite", "inf"), ("text", "abc")] {
+            let error = load(name, &format!("symbol,timestamp,price\nAAPL,1000,150\nAAPL,1001,{}\n", price)).unwrap_err();
+            assert!(error.contains(".csv:3: bad price"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde::Serialize;
+use crate::analytics::{EquitySnapshot, PerformanceReport};
+use crate::config::CostBasis;
+use crate::ledger::Side;
+use crate::portfolio::Portfolio;
+use crate::traders::Trader;
+
+// Bumped whenever a field is renamed or removed from the JSON or a column from the CSV files
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct EquityCurve {
+    pub trader_id: String,
+    pub snapshots: Vec<EquitySnapshot>,
+}
+
+// Everything known about a finished session, written to session.json
+#[derive(Debug, Serialize)]
+pub struct SessionReport {
+    pub schema_version: u32,
+    pub closed_at: u64, // Simulated milliseconds since the Unix epoch
+    pub session_secs: u64,
+    pub seed: Option<u64>,
+    pub cost_basis: CostBasis,
+    pub portfolios: Vec<Portfolio>,
+    pub performance: Vec<PerformanceReport>,
+    pub equity_curves: Vec<EquityCurve>,
+}
+
+impl SessionReport {
+    pub fn new(
+        closed_at: u64,
+        session_secs: u64,
+        seed: Option<u64>,
+        cost_basis: CostBasis,
+        traders: &[Trader],
+        portfolios: Vec<Portfolio>,
+        performance: Vec<PerformanceReport>,
+    ) -> Self {
+        let equity_curves = traders.iter()
+            .map(|t| EquityCurve { trader_id: t.id.clone(), snapshots: t.equity_curve.clone() })
+            .collect();
+        Self { schema_version: SCHEMA_VERSION, closed_at, session_secs, seed, cost_basis, portfolios, performance, equity_curves }
+    }
+
+    // Write session.json plus one CSV file per table into `dir`, creating it if needed
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("session.json"), serde_json::to_string_pretty(self)?)?;
+
+        let mut portfolios = Csv::new(&["trader_id", "cash_left", "total_amount", "profit_loss", "realized_pnl", "unrealized_pnl", "fees", "turnover", "pending_orders", "borrow_fees"]);
+        let mut positions = Csv::new(&["trader_id", "symbol", "quantity", "cost_basis", "latest_price", "realized_pnl", "unrealized_pnl", "fees", "turnover", "round_trips"]);
+        let mut ledger = Csv::new(&["trader_id", "timestamp", "order_id", "symbol", "side", "quantity", "price", "fees"]);
+        for portfolio in &self.portfolios {
+            portfolios.row(&[
+                &portfolio.trader_id, &portfolio.cash_left, &portfolio.total_amount, &portfolio.profit_loss, &portfolio.realized_pnl,
+                &portfolio.unrealized_pnl, &portfolio.fees, &portfolio.turnover, &portfolio.pending_orders.len(), &portfolio.borrow_fees,
+            ]);
+            for pnl in &portfolio.symbols {
+                positions.row(&[
+                    &portfolio.trader_id, &pnl.symbol, &pnl.quantity, &pnl.cost_basis, &pnl.latest_price,
+                    &pnl.realized_pnl, &pnl.unrealized_pnl, &pnl.fees, &pnl.turnover, &pnl.round_trips,
+                ]);
+            }
+            for fill in &portfolio.ledger {
+                let side = if fill.side == Side::Buy { "buy" } else { "sell" };
+                ledger.row(&[&portfolio.trader_id, &fill.timestamp, &fill.order_id, &fill.symbol, &side, &fill.quantity, &fill.price, &fill.fees]);
+            }
+        }
+
+        let mut performance = Csv::new(&[
+            "trader_id", "total_return", "volatility", "sharpe_ratio", "sortino_ratio", "max_drawdown", "max_drawdown_duration_ms",
+            "closed_trades", "hit_rate", "average_win", "average_loss", "profit_factor",
+        ]);
+        for report in &self.performance {
+            let profit_factor = report.profit_factor.map(|pf| pf.to_string()).unwrap_or_default();
+            performance.row(&[
+                &report.trader_id, &report.total_return, &report.volatility, &report.sharpe_ratio, &report.sortino_ratio, &report.max_drawdown,
+                &report.max_drawdown_duration_ms, &report.closed_trades, &report.hit_rate, &report.average_win, &report.average_loss, &profit_factor,
+            ]);
+        }
+
+        let mut equity = Csv::new(&["trader_id", "timestamp", "equity"]);
+        for curve in &self.equity_curves {
+            for snapshot in &curve.snapshots {
+                equity.row(&[&curve.trader_id, &snapshot.timestamp, &snapshot.equity]);
+            }
+        }
+
+        fs::write(dir.join("portfolios.csv"), portfolios.contents)?;
+        fs::write(dir.join("positions.csv"), positions.contents)?;
+        fs::write(dir.join("ledger.csv"), ledger.contents)?;
+        fs::write(dir.join("performance.csv"), performance.contents)?;
+        fs::write(dir.join("equity.csv"), equity.contents)?;
+        Ok(())
+    }
+}
+
+// Minimal CSV writer, fields holding a comma, quote or newline are quoted
+struct Csv {
+    contents: String,
+}
+
+impl Csv {
+    fn new(header: &[&str]) -> Self {
+        let mut csv = Self { contents: String::new() };
+        csv.row(&header.iter().map(|h| h as &dyn Display).collect::<Vec<_>>());
+        csv
+    }
+
+    fn row(&mut self, fields: &[&dyn Display]) {
+        let line: Vec<String> = fields.iter().map(|field| {
+            let value = field.to_string();
+            if value.contains([',', '"', '\n']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value
+            }
+        }).collect();
+        self.contents.push_str(&line.join(","));
+        self.contents.push('\n');
+    }
+}
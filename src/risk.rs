@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use crate::config::RiskLimits;
+use crate::models::{OrderRequest, OrderStatus, OrderStatusUpdate};
+use crate::traders::Trader;
+
+// Span of simulated time the order rate limit counts requests over
+const RATE_WINDOW_MS: u64 = 1000;
+
+// What a request would do if it went through, in the terms the limits are written in
+struct Exposure<'a> {
+    order_id: &'a str,
+    symbol: &'a str,
+    is_buy: bool,
+    quantity: u32, // Shares the order would leave open
+    limit_price: Option<f64>,
+}
+
+// Checks a broker runs on its traders' new orders and replaces before they reach the exchange.
+// Cancels only ever reduce risk and always go through.
+#[derive(Debug, Default)]
+pub struct PreTradeRisk {
+    limits: RiskLimits,
+    order_ids: HashSet<String>, // Every order id sent to the exchange so far
+    recent_requests: HashMap<String, VecDeque<u64>>, // When each trader's requests inside the rate window were sent
+}
+
+impl PreTradeRisk {
+    pub fn new(limits: RiskLimits) -> Self {
+        Self { limits, ..Self::default() }
+    }
+
+    // A new order reusing an id already sent. Its rejection is coded as a duplicate, since the
+    // trader tracks the first order under that id and would otherwise close it.
+    pub fn is_duplicate(&mut self, request: &OrderRequest) -> bool {
+        match request {
+            OrderRequest::New(order) => !self.order_ids.insert(order.order_id.clone()),
+            _ => false,
+        }
+    }
+
+    // Why the request breaches a limit, if it does
+    pub fn check(&mut self, request: &OrderRequest, trader: &Trader, last_prices: &HashMap<String, f64>, now: u64) -> Result<(), String> {
+        let exposure = match request {
+            OrderRequest::New(order) => Exposure {
+                order_id: &order.order_id,
+                symbol: &order.stock_symbol,
+                is_buy: order.order_type.is_buy(),
+                quantity: order.quantity,
+                limit_price: order.limit_price,
+            },
+            OrderRequest::Replace { order_id, stock_symbol, quantity, limit_price, .. } => {
+                let tracked = trader.orders.get(order_id).ok_or_else(|| format!("Unknown order {}", order_id))?;
+                Exposure {
+                    order_id,
+                    symbol: stock_symbol,
+                    is_buy: tracked.order.order_type.is_buy(),
+                    quantity: quantity.saturating_sub(tracked.cumulative_quantity),
+                    limit_price: Some(*limit_price),
+                }
+            }
+            OrderRequest::Cancel { .. } => return Ok(()),
+        };
+        self.throttle(&trader.id, now)?;
+
+        let last_price = last_prices.get(exposure.symbol).copied();
+        if let Some(max) = self.limits.max_order_quantity {
+            if exposure.quantity > max {
+                return Err(format!("{} shares is over the {} share order limit", exposure.quantity, max));
+            }
+        }
+        if let (Some(max), Some(price)) = (self.limits.max_order_notional, exposure.limit_price.or(last_price)) {
+            let notional = price * exposure.quantity as f64;
+            if notional > max {
+                return Err(format!("Order value ${:.2} is over the ${:.2} limit", notional, max));
+            }
+        }
+        if let (Some(percent), Some(limit_price), Some(last_price)) = (self.limits.price_collar_pct, exposure.limit_price, last_price) {
+            if (limit_price - last_price).abs() > last_price * percent / 100.0 {
+                return Err(format!("Limit price ${:.2} is more than {}% away from the last price ${:.2}", limit_price, percent, last_price));
+            }
+        }
+
+        let other_buys = || trader.orders.open_orders()
+            .filter(|o| o.order.order_type.is_buy() && o.order.order_id != exposure.order_id);
+        // Holdings either side at their last price, open buys at their limit, anything without a price at cost
+        let price_of = |symbol: &str, limit_price: Option<f64>| limit_price.or_else(|| last_prices.get(symbol).copied());
+        let order_price = price_of(exposure.symbol, exposure.limit_price).unwrap_or(0.0);
+
+        // A sell only adds to the position and exposure for the shares it sells short
+        let (position, order) = if exposure.is_buy {
+            let buying: u32 = other_buys().filter(|o| o.order.stock_symbol == exposure.symbol).map(|o| o.leaves_quantity).sum();
+            (trader.position(exposure.symbol) + buying as i64 + exposure.quantity as i64, order_price * exposure.quantity as f64)
+        } else {
+            let short = trader.shares_short(exposure.symbol);
+            let already_short = u32::try_from(-trader.position(exposure.symbol)).unwrap_or(0);
+            if short == already_short {
+                return Ok(());
+            }
+            (-(short as i64), order_price * (short - already_short) as f64)
+        };
+        if let Some(max) = self.limits.max_position {
+            if position.unsigned_abs() > max as u64 {
+                return Err(format!("Position of {} shares of {} would be over the {} share limit", position, exposure.symbol, max));
+            }
+        }
+        if let Some(max) = self.limits.max_gross_exposure {
+            let held: f64 = trader.portfolio.iter()
+                .map(|p| price_of(&p.symbol, None).unwrap_or(p.average_cost) * p.quantity.abs() as f64)
+                .sum();
+            let buying: f64 = other_buys()
+                .map(|o| price_of(&o.order.stock_symbol, o.order.limit_price).unwrap_or(0.0) * o.leaves_quantity as f64)
+                .sum();
+            let gross = held + buying + order;
+            if gross > max {
+                return Err(format!("Gross exposure ${:.2} would be over the ${:.2} limit", gross, max));
+            }
+        }
+        Ok(())
+    }
+
+    // Count a request against the trader's rate, refusing it once the window is full
+    fn throttle(&mut self, trader_id: &str, now: u64) -> Result<(), String> {
+        let Some(max) = self.limits.max_orders_per_sec else { return Ok(()) };
+        let recent = self.recent_requests.entry(trader_id.to_string()).or_default();
+        while recent.front().is_some_and(|sent| now.saturating_sub(*sent) >= RATE_WINDOW_MS) {
+            recent.pop_front();
+        }
+        if recent.len() >= max as usize {
+            return Err(format!("More than {} orders a second", max));
+        }
+        recent.push_back(now);
+        Ok(())
+    }
+}
+
+// Report sent back to the trader for a request the broker refused. A refused new order is
+// rejected, a refused replace leaves the order as it was.
+pub fn rejection(request: &OrderRequest, reason: String, timestamp: u64) -> Option<OrderStatusUpdate> {
+    let (order_id, trader_id, stock_symbol, status, leaves_quantity) = match request {
+        OrderRequest::New(order) => (&order.order_id, &order.trader_id, &order.stock_symbol, OrderStatus::Rejected, order.quantity),
+        OrderRequest::Replace { order_id, trader_id, stock_symbol, .. } => (order_id, trader_id, stock_symbol, OrderStatus::CancelRejected, 0),
+        OrderRequest::Cancel { .. } => return None,
+    };
+    Some(OrderStatusUpdate {
+        order_id: order_id.clone(),
+        trader_id: trader_id.clone(),
+        stock_symbol: stock_symbol.clone(),
+        status,
+        fill_quantity: 0,
+        fill_price: None,
+        cumulative_quantity: 0,
+        leaves_quantity,
+        timestamp,
+        reason: Some(reason),
+        liquidity: None,
+        fees: 0.0,
+        reject_code: None,
+    })
+}
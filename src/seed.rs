@@ -0,0 +1,33 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+// Hands out a random number generator per component.
+// With a seed every component gets its own reproducible stream, so adding draws in one
+// component does not shift the numbers another one sees. Without a seed they come from entropy.
+#[derive(Debug, Clone, Copy)]
+pub struct SeedSource {
+    seed: Option<u64>,
+}
+
+impl SeedSource {
+    pub fn new(seed: Option<u64>) -> Self {
+        Self { seed }
+    }
+
+    pub fn rng_for(&self, component: &str) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(derive_seed(seed, component)),
+            None => StdRng::from_entropy(),
+        }
+    }
+}
+
+// FNV-1a over the global seed and the component name, stable across platforms and Rust versions
+fn derive_seed(seed: u64, component: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in seed.to_le_bytes().iter().chain(component.as_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
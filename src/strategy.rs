@@ -0,0 +1,494 @@
+use std::collections::{HashMap, VecDeque};
+use rand::rngs::StdRng;
+use rand::Rng;
+use crate::config::{ExitConfig, StrategyConfig};
+use crate::models::{OrderType, Stock, TimeInForce, Trail};
+use crate::traders::Trader;
+
+// An order a strategy wants placed, pulled or repriced. The trader checks it can afford it before sending it to the broker.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderIntent {
+    Submit {
+        symbol: String,
+        order_type: OrderType,
+        quantity: u32,
+        limit_price: Option<f64>,
+        time_in_force: TimeInForce,
+        display_quantity: Option<u32>, // Show only this much of a limit order in the book
+    },
+    // Stop, stop-limit or trailing stop, held by the exchange until its trigger is reached
+    SubmitStop {
+        symbol: String,
+        order_type: OrderType,
+        quantity: u32,
+        limit_price: Option<f64>,
+        stop_price: Option<f64>,
+        trail: Option<Trail>,
+        time_in_force: TimeInForce,
+    },
+    Cancel {
+        order_id: String,
+    },
+    // Buy at market, or at a limit, with a take-profit limit sell and a stop-loss sell that go live
+    // for the bought shares once the entry is done trading
+    Bracket {
+        symbol: String,
+        quantity: u32,
+        limit_price: Option<f64>,
+        take_profit: f64,
+        stop_loss: f64,
+    },
+    // Take-profit limit sell and stop-loss sell on held shares, whichever trades first cancels the other
+    Oco {
+        symbol: String,
+        quantity: u32,
+        take_profit: f64,
+        stop_loss: f64,
+    },
+    // New total quantity and limit price for a resting limit order
+    Replace {
+        order_id: String,
+        quantity: u32,
+        limit_price: f64,
+    },
+}
+
+// Trading logic run by a trader on every market update it receives
+pub trait Strategy: Send {
+    fn on_market_update(&mut self, stock: &Stock, trader: &Trader) -> Vec<OrderIntent>;
+}
+
+impl Strategy for Box<dyn Strategy> {
+    fn on_market_update(&mut self, stock: &Stock, trader: &Trader) -> Vec<OrderIntent> {
+        (**self).on_market_update(stock, trader)
+    }
+}
+
+pub fn build_strategy(config: &StrategyConfig, rng: StdRng) -> Box<dyn Strategy> {
+    match config.clone() {
+        StrategyConfig::Random => Box::new(RandomStrategy { rng }),
+        StrategyConfig::MovingAverageCrossover { fast_window, slow_window, quantity, exits } => {
+            Box::new(MovingAverageCrossover { fast_window, slow_window, quantity, exits, history: HashMap::new() })
+        }
+        StrategyConfig::BollingerBands { window, num_std_dev, quantity, trailing_stop_pct } => {
+            Box::new(BollingerBands { window, num_std_dev, quantity, trailing_stop_pct, history: HashMap::new() })
+        }
+        StrategyConfig::MarketMaker { symbols, half_spread_bps, quantity, max_inventory, inventory_skew, display_quantity } => {
+            Box::new(MarketMaker { symbols, half_spread_bps, quantity, max_inventory, inventory_skew, display_quantity })
+        }
+        StrategyConfig::Pairs { first, second, window, entry_z, exit_z, quantity } => Box::new(PairsTrader {
+            first,
+            second,
+            entry_z,
+            exit_z,
+            quantity,
+            last_first: None,
+            last_second: None,
+            first_history: RollingWindow::new(window),
+            second_history: RollingWindow::new(window),
+            baseline: None,
+        }),
+    }
+}
+
+// Shares held long, none when flat or short
+fn held_quantity(trader: &Trader, symbol: &str) -> u32 {
+    u32::try_from(trader.position(symbol)).unwrap_or(0)
+}
+
+fn market_order(symbol: &str, order_type: OrderType, quantity: u32) -> OrderIntent {
+    OrderIntent::Submit { symbol: symbol.to_string(), order_type, quantity, limit_price: None, time_in_force: TimeInForce::Day, display_quantity: None }
+}
+
+// Fixed size window of the most recent values
+#[derive(Debug, Clone)]
+struct RollingWindow {
+    values: VecDeque<f64>,
+    capacity: usize,
+}
+
+impl RollingWindow {
+    fn new(capacity: usize) -> Self {
+        Self { values: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    fn push(&mut self, value: f64) {
+        if self.values.len() == self.capacity {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+    }
+
+    fn is_full(&self) -> bool {
+        self.values.len() == self.capacity
+    }
+
+    fn mean(&self) -> f64 {
+        self.values.iter().sum::<f64>() / self.values.len() as f64
+    }
+
+    // Mean of the most recent `count` values
+    fn recent_mean(&self, count: usize) -> f64 {
+        self.values.iter().rev().take(count).sum::<f64>() / count.min(self.values.len()) as f64
+    }
+
+    fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        (self.values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / self.values.len() as f64).sqrt()
+    }
+}
+
+// Coin-flipping trader: rarely buys, often sells, leaning against the latest price move
+pub struct RandomStrategy {
+    rng: StdRng,
+}
+
+impl Strategy for RandomStrategy {
+    fn on_market_update(&mut self, stock: &Stock, trader: &Trader) -> Vec<OrderIntent> {
+        let price_change = stock.price_change.percentage;
+        // Default probabilities
+        let mut buy_probability = 0.005;
+        let mut sell_probability = 0.3;
+
+        // Adjust probabilities based on price change
+        if price_change > 0.0 {
+            // Increase sell probability and decrease buy probability if price is rising
+            sell_probability += price_change / 1000.0;
+            buy_probability -= price_change / 1000.0;
+        } else {
+            // Increase buy probability and decrease sell probability if price is falling
+            buy_probability += price_change.abs() / 1000.0;
+            sell_probability -= price_change.abs() / 1000.0;
+        }
+
+        let rng = &mut self.rng;
+        let decision_probability: f64 = rng.gen_range(0.000..1.000);
+        let intent = if decision_probability < buy_probability {
+            // Market buy or limit buy
+            let quantity = rng.gen_range(1..=3);
+            if rng.gen_bool(0.5) {
+                market_order(&stock.symbol, OrderType::MarketBuy, quantity)
+            } else {
+                let limit_price = rng.gen_range(stock.price * 0.95..=stock.price);
+                let time_in_force = random_time_in_force(rng);
+                OrderIntent::Submit { symbol: stock.symbol.clone(), order_type: OrderType::LimitBuy, quantity, limit_price: Some(limit_price), time_in_force, display_quantity: None }
+            }
+        } else if decision_probability < buy_probability + sell_probability {
+            // Market sell or limit sell, only for stocks the trader holds
+            let held = held_quantity(trader, &stock.symbol);
+            if held == 0 {
+                return Vec::new();
+            }
+            let quantity = rng.gen_range(1..=held);
+            if rng.gen_bool(0.5) {
+                market_order(&stock.symbol, OrderType::MarketSell, quantity)
+            } else {
+                let limit_price = rng.gen_range(stock.price..=stock.price * 1.05);
+                let time_in_force = random_time_in_force(rng);
+                OrderIntent::Submit { symbol: stock.symbol.clone(), order_type: OrderType::LimitSell, quantity, limit_price: Some(limit_price), time_in_force, display_quantity: None }
+            }
+        } else {
+            // Hold decision
+            return Vec::new();
+        };
+        vec![intent]
+    }
+}
+
+// Mostly DAY limit orders, with some that outlive the session or must trade at once
+fn random_time_in_force(rng: &mut StdRng) -> TimeInForce {
+    match rng.gen_range(0..10) {
+        0..=5 => TimeInForce::Day,
+        6..=7 => TimeInForce::Gtc,
+        8 => TimeInForce::Ioc,
+        _ => TimeInForce::Fok,
+    }
+}
+
+// Momentum: buy when the fast moving average crosses above the slow one, sell the position when it crosses back below.
+// With exits every entry is a bracket and held shares are kept covered by a take-profit and a stop-loss
+// while the trend is up, once it turns the exits are pulled and the position sold.
+pub struct MovingAverageCrossover {
+    fast_window: usize,
+    slow_window: usize,
+    quantity: u32,
+    exits: Option<ExitConfig>,
+    history: HashMap<String, (RollingWindow, Option<bool>)>, // Prices and whether fast was above slow last time
+}
+
+impl Strategy for MovingAverageCrossover {
+    fn on_market_update(&mut self, stock: &Stock, trader: &Trader) -> Vec<OrderIntent> {
+        let (prices, was_above) = self.history.entry(stock.symbol.clone())
+            .or_insert_with(|| (RollingWindow::new(self.slow_window), None));
+        prices.push(stock.price);
+        if !prices.is_full() {
+            return Vec::new();
+        }
+
+        let is_above = prices.recent_mean(self.fast_window) > prices.mean();
+        let crossed = was_above.is_some_and(|was_above| was_above != is_above);
+        *was_above = Some(is_above);
+        let held = held_quantity(trader, &stock.symbol);
+        if let Some(exits) = self.exits {
+            let open: Vec<_> = trader.pending_orders().filter(|o| o.stock_symbol == stock.symbol).collect();
+            // Wait while a cancel or replace is unanswered, or while the market sell exit is still in flight
+            if open.iter().any(|o| trader.has_pending_request(&o.order_id) || matches!(o.order_type, OrderType::MarketSell)) {
+                return Vec::new();
+            }
+            let take_profit = stock.price * (1.0 + exits.take_profit_pct / 100.0);
+            let stop_loss = stock.price * (1.0 - exits.stop_loss_pct / 100.0);
+            return match (is_above, open.first()) {
+                (true, None) if crossed => vec![OrderIntent::Bracket { symbol: stock.symbol.clone(), quantity: self.quantity, limit_price: None, take_profit, stop_loss }],
+                (true, None) if held > 0 => vec![OrderIntent::Oco { symbol: stock.symbol.clone(), quantity: held, take_profit, stop_loss }],
+                // Pulling one exit cancels the other, the shares are sold on a later update
+                (false, Some(order)) => vec![OrderIntent::Cancel { order_id: order.order_id.clone() }],
+                (false, None) if held > 0 => vec![market_order(&stock.symbol, OrderType::MarketSell, held)],
+                _ => Vec::new(),
+            };
+        }
+        if !crossed || trader.has_pending_orders(&stock.symbol) {
+            return Vec::new();
+        }
+
+        if is_above {
+            vec![market_order(&stock.symbol, OrderType::MarketBuy, self.quantity)]
+        } else if held > 0 {
+            vec![market_order(&stock.symbol, OrderType::MarketSell, held)]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+// Mean reversion: buy below the lower Bollinger band, sell the position once the price is back above the mean.
+// With a trailing stop the position is also protected by a stop-loss that follows the price up.
+pub struct BollingerBands {
+    window: usize,
+    num_std_dev: f64,
+    quantity: u32,
+    trailing_stop_pct: Option<f64>,
+    history: HashMap<String, RollingWindow>,
+}
+
+impl Strategy for BollingerBands {
+    fn on_market_update(&mut self, stock: &Stock, trader: &Trader) -> Vec<OrderIntent> {
+        let prices = self.history.entry(stock.symbol.clone()).or_insert_with(|| RollingWindow::new(self.window));
+        prices.push(stock.price);
+        if !prices.is_full() {
+            return Vec::new();
+        }
+        // The stop-loss rests for as long as the position is held, any other open order means wait
+        let (stops, working): (Vec<_>, Vec<_>) = trader.pending_orders()
+            .filter(|o| o.stock_symbol == stock.symbol)
+            .partition(|o| o.order_type.is_stop());
+        let stop = stops.first();
+        if !working.is_empty() || stop.is_some_and(|o| trader.has_pending_request(&o.order_id)) {
+            return Vec::new();
+        }
+
+        let mean = prices.mean();
+        let lower_band = mean - self.num_std_dev * prices.std_dev();
+        let held = held_quantity(trader, &stock.symbol);
+        if stock.price < lower_band {
+            vec![market_order(&stock.symbol, OrderType::MarketBuy, self.quantity)]
+        } else if stock.price > mean && held > 0 {
+            // The stop holds the shares, pull it and sell on a later update
+            match stop {
+                Some(stop) => vec![OrderIntent::Cancel { order_id: stop.order_id.clone() }],
+                None => vec![market_order(&stock.symbol, OrderType::MarketSell, held)],
+            }
+        } else if let Some(percent) = self.trailing_stop_pct.filter(|_| held > 0 && stop.is_none()) {
+            vec![OrderIntent::SubmitStop {
+                symbol: stock.symbol.clone(),
+                order_type: OrderType::TrailingStopSell,
+                quantity: held,
+                limit_price: None,
+                stop_price: None,
+                trail: Some(Trail::Percent(percent)),
+                time_in_force: TimeInForce::Gtc, // A stop-loss stays until the position is closed
+            }]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+// Two-sided quotes around the last price. The quotes are shifted against the inventory,
+// so a long market maker quotes lower to sell down and buys less eagerly.
+pub struct MarketMaker {
+    symbols: Vec<String>,
+    half_spread_bps: f64,
+    quantity: u32,
+    max_inventory: u32,
+    inventory_skew: f64, // Fraction of the half spread the quotes move at full inventory
+    display_quantity: Option<u32>, // Quote as icebergs showing this much
+}
+
+impl Strategy for MarketMaker {
+    fn on_market_update(&mut self, stock: &Stock, trader: &Trader) -> Vec<OrderIntent> {
+        if !self.symbols.contains(&stock.symbol) {
+            return Vec::new();
+        }
+
+        let inventory = held_quantity(trader, &stock.symbol);
+        let half_spread = stock.price * self.half_spread_bps / 10_000.0;
+        let inventory_ratio = inventory as f64 / self.max_inventory.max(1) as f64;
+        let reservation_price = stock.price - self.inventory_skew * inventory_ratio * half_spread;
+
+        // Each side is quoted while there is room to buy or inventory to sell
+        let bid = (inventory < self.max_inventory).then(|| (reservation_price - half_spread, self.quantity.min(self.max_inventory - inventory)));
+        let ask = (inventory > 0).then(|| (reservation_price + half_spread, self.quantity.min(inventory)));
+
+        let mut intents = Vec::new();
+        for (order_type, quote) in [(OrderType::LimitBuy, bid), (OrderType::LimitSell, ask)] {
+            let resting = trader.pending_orders().find(|o| o.stock_symbol == stock.symbol && o.order_type == order_type);
+            match (resting, quote) {
+                (None, Some((price, quantity))) => intents.push(OrderIntent::Submit {
+                    symbol: stock.symbol.clone(),
+                    order_type,
+                    quantity,
+                    limit_price: Some(price),
+                    time_in_force: TimeInForce::Day,
+                    display_quantity: self.display_quantity,
+                }),
+                (Some(order), _) if trader.has_pending_request(&order.order_id) => {}
+                // Pull a side that should no longer be quoted
+                (Some(order), None) => intents.push(OrderIntent::Cancel { order_id: order.order_id.clone() }),
+                // Reprice a stale quote once the market has moved away by more than half the half spread
+                (Some(order), Some((price, _))) => {
+                    if order.limit_price.is_some_and(|limit| (limit - price).abs() > half_spread / 2.0) {
+                        intents.push(OrderIntent::Replace { order_id: order.order_id.clone(), quantity: order.quantity, limit_price: price });
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+        intents
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PairPosition {
+    Flat,
+    LongFirst,
+    LongSecond,
+}
+
+// Statistical arbitrage on two cointegrated symbols. The hedge ratio comes from a rolling
+// regression of the log prices; when the spread strays `entry_z` standard deviations from its
+// mean the rich leg is sold, short if the trader may, or else only what is held, and the cheap
+// one bought, the second leg sized by the hedge ratio. Both legs are unwound once it is back
+// within `exit_z`. Where the pair stands is read from the shares its fills moved, so orders
+// that are rejected or never trade leave it flat.
+pub struct PairsTrader {
+    first: String,
+    second: String,
+    entry_z: f64,
+    exit_z: f64,
+    quantity: u32, // Shares of the first leg, the second is sized to hedge them
+    last_first: Option<f64>,
+    last_second: Option<f64>,
+    first_history: RollingWindow,
+    second_history: RollingWindow,
+    baseline: Option<(i64, i64)>, // Shares of each leg held before the pair first traded
+}
+
+impl PairsTrader {
+    // z-score of the latest spread ln(first) - beta * ln(second) over the window, with beta
+    fn spread_z_score(&self) -> Option<(f64, f64)> {
+        let xs = &self.second_history.values;
+        let ys = &self.first_history.values;
+        let (x_mean, y_mean) = (self.second_history.mean(), self.first_history.mean());
+        let covariance: f64 = xs.iter().zip(ys).map(|(x, y)| (x - x_mean) * (y - y_mean)).sum();
+        let variance: f64 = xs.iter().map(|x| (x - x_mean).powi(2)).sum();
+        if variance <= f64::EPSILON {
+            return None;
+        }
+        let beta = covariance / variance;
+
+        let spreads: Vec<f64> = xs.iter().zip(ys).map(|(x, y)| y - beta * x).collect();
+        let mean = spreads.iter().sum::<f64>() / spreads.len() as f64;
+        let std_dev = (spreads.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / spreads.len() as f64).sqrt();
+        if std_dev <= f64::EPSILON {
+            return None;
+        }
+        spreads.last().map(|latest| ((latest - mean) / std_dev, beta))
+    }
+
+    // Shares of the second leg that hedge the first: beta is the elasticity of the first leg's
+    // price to the second's, so each dollar of the first is offset by beta dollars of the second
+    fn hedge_quantity(&self, beta: f64, first: f64, second: f64) -> u32 {
+        (self.quantity as f64 * beta * first / second).round() as u32
+    }
+}
+
+impl Strategy for PairsTrader {
+    fn on_market_update(&mut self, stock: &Stock, trader: &Trader) -> Vec<OrderIntent> {
+        if stock.symbol == self.first {
+            self.last_first = Some(stock.price);
+        } else if stock.symbol == self.second {
+            self.last_second = Some(stock.price);
+        } else {
+            return Vec::new();
+        }
+        // Sample the pair once both legs have moved, on every update of the first leg
+        let (Some(first), Some(second)) = (self.last_first, self.last_second) else { return Vec::new() };
+        if stock.symbol != self.first {
+            return Vec::new();
+        }
+        self.first_history.push(first.ln());
+        self.second_history.push(second.ln());
+        if !self.first_history.is_full()
+            || trader.has_pending_orders(&self.first)
+            || trader.has_pending_orders(&self.second)
+        {
+            return Vec::new();
+        }
+        let Some((z_score, beta)) = self.spread_z_score() else { return Vec::new() };
+
+        // Shares of each leg the pair's fills added or took away
+        let (first_base, second_base) = *self.baseline.get_or_insert_with(|| (trader.position(&self.first), trader.position(&self.second)));
+        let (first_leg, second_leg) = (trader.position(&self.first) - first_base, trader.position(&self.second) - second_base);
+        let position = if first_leg > 0 || second_leg < 0 {
+            PairPosition::LongFirst
+        } else if second_leg > 0 || first_leg < 0 {
+            PairPosition::LongSecond
+        } else {
+            PairPosition::Flat
+        };
+
+        let mut intents = Vec::new();
+        let mut sell = |symbol: &str, quantity: u32| {
+            let quantity = if trader.short_selling { quantity } else { held_quantity(trader, symbol).min(quantity) };
+            if quantity > 0 {
+                intents.push(market_order(symbol, OrderType::MarketSell, quantity));
+            }
+        };
+        let hedge = self.hedge_quantity(beta, first, second);
+        match position {
+            // A pair moving against each other cannot be hedged, it is left alone
+            PairPosition::Flat if beta <= 0.0 || hedge == 0 => {}
+            PairPosition::Flat if z_score > self.entry_z => {
+                // First leg is rich relative to the second
+                sell(&self.first, self.quantity);
+                intents.push(market_order(&self.second, OrderType::MarketBuy, hedge));
+            }
+            PairPosition::Flat if z_score < -self.entry_z => {
+                sell(&self.second, hedge);
+                intents.push(market_order(&self.first, OrderType::MarketBuy, self.quantity));
+            }
+            PairPosition::LongFirst | PairPosition::LongSecond if z_score.abs() < self.exit_z => {
+                // Sell what the pair bought and buy back what it sold
+                for (symbol, shares) in [(&self.first, first_leg), (&self.second, second_leg)] {
+                    if shares > 0 {
+                        intents.push(market_order(symbol, OrderType::MarketSell, shares as u32));
+                    } else if shares < 0 {
+                        intents.push(market_order(symbol, OrderType::MarketBuy, shares.unsigned_abs() as u32));
+                    }
+                }
+            }
+            _ => {}
+        }
+        intents
+    }
+}
@@ -1,93 +1,445 @@
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
-use crate::models::{Stock, Order, OrderType, PriceChange};
-use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
+use crate::models::{Stock, Order, OrderRequest, OrderStatus, OrderStatusUpdate, OrderType, RejectCode, TimeInForce, Trail};
+use crate::order_tracker::{OrderGroup, OrderState, OrderTracker, Transition};
+use std::collections::HashMap;
 use std::sync::Arc;
 use crate::color::print_colored; // Import the print_colored function
+use crate::config::{FeeSchedule, TraderConfig};
+use crate::ledger::{Fill, Side};
+use crate::analytics::EquitySnapshot;
+use crate::strategy::{OrderIntent, Strategy};
 
 #[derive(Debug, Clone)]
 pub struct OwnedPosition {
     pub symbol: String,
-    pub quantity: u32,
-    pub average_cost: f64,
+    pub quantity: i64, // Negative for shares sold short
+    pub average_cost: f64, // Average price the shares were sold at for a short position
 }
 
+// Market buys have no limit, so buying power is held back at the last price plus this margin
+const MARKET_BUY_COLLAR: f64 = 0.05;
+
+// Cash a short sale sets aside with its proceeds, as a fraction of its value, until the shares are bought back
+const SHORT_MARGIN: f64 = 0.5;
+
+// Allowed rounding drift when reconciling the books
+const RECONCILE_TOLERANCE: f64 = 1e-6;
+
 #[derive(Debug, Clone)]
 pub struct Trader {
     pub id: String,
-    pub cash: f64,
+    pub cash: f64, // Free cash, not held back by any open order
+    pub reserved_cash: f64, // Cash held back for open buy orders
     pub portfolio: Vec<OwnedPosition>,
-    pub pending_orders: Vec<Order>,
+    pub orders: OrderTracker, // Every order sent this session with its state and reservation
     pub order_counter: u64, // Counter for generating unique order IDs
+    pub initial_value: f64, // Starting cash plus the cost of starting holdings, the baseline for profit/loss
+    pub realized_pnl: f64, // Profit booked by sells against the average cost
+    pub opening_positions: Vec<OwnedPosition>, // Holdings the trader started the session with
+    pub ledger: Vec<Fill>, // Every fill in the order it was received
+    pub equity_curve: Vec<EquitySnapshot>,
+    pub short_selling: bool, // May sell shares it does not hold
+    pub margin_account: bool, // May borrow to buy, its cash goes negative by what it owes the broker
+    pub borrow_fees: f64, // Paid to the broker for shares on loan
+    pub fee_schedule: FeeSchedule, // What its broker charges on a fill
 }
 
 
 impl Trader {
-    pub fn new(id: String) -> Self {
+    pub fn new(id: String, cash: f64) -> Self {
         Self {
             id,
-            cash: 5000.0,
+            cash,
+            reserved_cash: 0.0,
             portfolio: Vec::new(),
-            pending_orders: Vec::new(),
+            orders: OrderTracker::default(),
             order_counter: 0, // Initialize the order counter
+            initial_value: cash,
+            realized_pnl: 0.0,
+            opening_positions: Vec::new(),
+            ledger: Vec::new(),
+            equity_curve: Vec::new(),
+            short_selling: false,
+            margin_account: false,
+            borrow_fees: 0.0,
+            fee_schedule: FeeSchedule::default(),
         }
     }
 
-    pub fn add_pending_order(&mut self, order: Order) {
-        self.pending_orders.push(order);
+    pub fn from_config(config: &TraderConfig, fee_schedule: &FeeSchedule) -> Self {
+        let mut trader = Self::new(config.id.clone(), config.initial_cash);
+        for holding in &config.holdings {
+            trader.portfolio.push(OwnedPosition {
+                symbol: holding.symbol.clone(),
+                quantity: holding.quantity as i64,
+                average_cost: holding.average_cost,
+            });
+            trader.initial_value += holding.average_cost * holding.quantity as f64;
+        }
+        trader.opening_positions = trader.portfolio.clone();
+        trader.short_selling = config.short_selling;
+        trader.margin_account = config.margin_account;
+        trader.fee_schedule = fee_schedule.clone();
+        trader
     }
 
-    pub fn complete_order(&mut self, order: &Order, stock_price: f64) -> Result<(), String> {
-        match order.order_type {
-            OrderType::MarketBuy | OrderType::LimitBuy => {
-                let stock = Stock {
-                    symbol: order.stock_symbol.clone(),
-                    price: stock_price,
-                    price_change: PriceChange { percentage: 0.0, absolute: 0.0 },
-                };
-                self.buy_stock(stock, order.quantity)?;
-                print_colored(&format!("Trader {} bought {} shares of {} at ${:.2} each.", self.id, order.quantity, order.stock_symbol, stock_price), "green");
+    // Cash, including what open orders and short sales hold back, plus holdings at their latest price
+    // or at cost when never quoted. Shares sold short count against it at what buying them back costs.
+    pub fn equity(&self, latest: &HashMap<String, Stock>) -> f64 {
+        let holdings: f64 = self.portfolio.iter()
+            .map(|p| latest.get(&p.symbol).map(|s| s.price).unwrap_or(p.average_cost) * p.quantity as f64)
+            .sum();
+        self.cash + self.reserved_cash + self.short_collateral() + holdings
+    }
+
+    // Shares held of a symbol, negative when short
+    pub fn position(&self, symbol: &str) -> i64 {
+        self.portfolio.iter().find(|p| p.symbol == symbol).map(|p| p.quantity).unwrap_or(0)
+    }
+
+    // Proceeds of the open short sales and the margin set aside with them
+    fn short_collateral(&self) -> f64 {
+        self.portfolio.iter()
+            .filter(|p| p.quantity < 0)
+            .map(|p| p.average_cost * -p.quantity as f64 * (1.0 + SHORT_MARGIN))
+            .sum()
+    }
+
+    // Track a new order and hold back what it needs: `cash` moves from free to reserved for buys.
+    // An order id already in use is refused before any cash moves.
+    pub fn add_pending_order(&mut self, order: Order, cash: f64) -> Result<(), String> {
+        self.orders.insert(order, cash)?;
+        self.cash -= cash;
+        self.reserved_cash += cash;
+        Ok(())
+    }
+
+    // Track a group of new orders. A bracket's legs wait unsent, holding nothing, until its entry
+    // is done trading; the legs of a plain one-cancels-other group are live straight away.
+    fn add_order_group(&mut self, parent: Option<(Order, f64)>, legs: Vec<Order>) -> Result<(), String> {
+        let ids = parent.iter().map(|(order, _)| order).chain(&legs).map(|order| &order.order_id);
+        if let Some(order_id) = ids.clone().find(|order_id| self.orders.get(order_id).is_some()) {
+            return Err(format!("Order {} is already tracked", order_id));
+        }
+        let group = OrderGroup {
+            parent: parent.as_ref().map(|(order, _)| order.order_id.clone()),
+            legs: legs.iter().map(|leg| leg.order_id.clone()).collect(),
+        };
+        let held = parent.is_some();
+        if let Some((order, cash)) = parent {
+            self.add_pending_order(order, cash)?;
+        }
+        for leg in legs {
+            if held {
+                self.orders.insert_held(leg)?;
+            } else {
+                self.add_pending_order(leg, 0.0)?;
             }
-            OrderType::MarketSell | OrderType::LimitSell => {
-                self.sell_stock(&order.stock_symbol, order.quantity)?;
-                print_colored(&format!("Trader {} sold {} shares of {} at ${:.2} each.", self.id, order.quantity, order.stock_symbol, stock_price), "red");
+        }
+        self.orders.link(group);
+        Ok(())
+    }
+
+    // Keep an order's group consistent after a report on it: once a bracket's entry is done trading its
+    // legs go live for the shares it bought, and a leg that trades or closes cancels the other legs.
+    // Safe to call again for a report already seen.
+    pub fn group_requests(&mut self, order_id: &str) -> Vec<OrderRequest> {
+        let Some(group) = self.orders.group_of(order_id).cloned() else { return Vec::new() };
+        let Some((state, filled)) = self.orders.get(order_id).map(|t| (t.state, t.cumulative_quantity)) else { return Vec::new() };
+        if group.parent.as_deref() == Some(order_id) {
+            return if state.is_terminal() { self.activate_legs(&group.legs, filled) } else { Vec::new() };
+        }
+        if filled == 0 && !state.is_terminal() {
+            return Vec::new();
+        }
+        // A leg closing untraded leaves alone a leg that has already traded, which is what cancelled it
+        let siblings: Vec<String> = group.legs.iter()
+            .filter(|leg| *leg != order_id)
+            .filter(|leg| filled > 0 || self.orders.get(leg).is_some_and(|t| t.cumulative_quantity == 0))
+            .cloned()
+            .collect();
+        siblings.iter().filter_map(|leg| self.request_cancel(leg)).collect()
+    }
+
+    // Send the held legs of a bracket for `quantity` shares, or drop them if there is nothing to sell
+    fn activate_legs(&mut self, legs: &[String], quantity: u32) -> Vec<OrderRequest> {
+        let held: Vec<&String> = legs.iter().filter(|leg| self.orders.get(leg).is_some_and(|t| t.state == OrderState::Held)).collect();
+        let Some(symbol) = held.first().and_then(|leg| self.orders.get(leg)).map(|t| t.order.stock_symbol.clone()) else { return Vec::new() };
+        // The legs sell the same shares, so they are checked once
+        if quantity == 0 || self.available_quantity(&symbol) < quantity {
+            for leg in held {
+                let _ = self.orders.close(leg, OrderState::Cancelled);
             }
+            print_colored(&format!("Trader {} dropped the exits of a bracket on {}, no shares to protect.", self.id, symbol), "yellow");
+            return Vec::new();
         }
-        // Remove the pending order after processing
-        self.remove_pending_order(&order.order_id);
+        print_colored(&format!("Trader {} bracket on {} filled, exits for {} shares are live.", self.id, symbol, quantity), "yellow");
+        let legs: Vec<String> = held.into_iter().cloned().collect();
+        legs.iter().filter_map(|leg| self.orders.activate(leg, quantity).cloned()).map(OrderRequest::New).collect()
+    }
+
+    // Orders that can still trade
+    pub fn pending_orders(&self) -> impl Iterator<Item = &Order> {
+        self.orders.open_orders().map(|tracked| &tracked.order)
+    }
+
+    pub fn has_pending_orders(&self, symbol: &str) -> bool {
+        self.pending_orders().any(|o| o.stock_symbol == symbol)
+    }
+
+    // Whether a cancel or replace for the order is still waiting for an answer
+    pub fn has_pending_request(&self, order_id: &str) -> bool {
+        self.orders.get(order_id).is_some_and(|tracked| tracked.has_pending_request())
+    }
+
+    // Ask for an open order to be pulled from the book
+    pub fn request_cancel(&mut self, order_id: &str) -> Option<OrderRequest> {
+        let tracked = self.orders.get_mut(order_id).filter(|t| t.is_open() && !t.pending_cancel)?;
+        if tracked.state == OrderState::Held {
+            // Never sent, so it is dropped here along with the other legs waiting on the same entry
+            let legs = self.orders.group_of(order_id).map(|group| group.legs.clone()).unwrap_or_default();
+            for leg in legs {
+                let _ = self.orders.close(&leg, OrderState::Cancelled);
+            }
+            return None;
+        }
+        tracked.pending_cancel = true;
+        Some(OrderRequest::Cancel {
+            order_id: order_id.to_string(),
+            trader_id: tracked.order.trader_id.clone(),
+            stock_symbol: tracked.order.stock_symbol.clone(),
+        })
+    }
+
+    // Ask for a resting limit order to get a new total quantity and price.
+    // A buy that needs more cash has the difference reserved now, the reservation only
+    // shrinks once the exchange confirms since the order can still fill at its old price.
+    pub fn request_replace(&mut self, order_id: &str, quantity: u32, limit_price: f64) -> Option<OrderRequest> {
+        let tracked = self.orders.get(order_id)
+            .filter(|t| t.is_open() && t.state != OrderState::Held && !t.has_pending_request())
+            .filter(|t| t.order.limit_price.is_some() && !t.order.order_type.is_stop())?;
+        if quantity <= tracked.cumulative_quantity || limit_price <= 0.0 {
+            return None;
+        }
+        let new_leaves = quantity - tracked.cumulative_quantity;
+        let is_buy = tracked.order.order_type.is_buy();
+        let (symbol, trader_id, leaves, reserved) =
+            (tracked.order.stock_symbol.clone(), tracked.order.trader_id.clone(), tracked.leaves_quantity, tracked.reserved_cash);
+
+        let needed = limit_price * new_leaves as f64 + self.fee_schedule.most_for(new_leaves, limit_price);
+        let extra_cash = if is_buy { (needed - reserved).max(0.0) } else { 0.0 };
+        if extra_cash > self.cash || (!is_buy && new_leaves > leaves + self.available_quantity(&symbol)) {
+            return None;
+        }
+        self.cash -= extra_cash;
+        self.reserved_cash += extra_cash;
+        let tracked = self.orders.get_mut(order_id)?;
+        tracked.reserved_cash += extra_cash;
+        tracked.pending_replace = Some((quantity, limit_price));
+        Some(OrderRequest::Replace { order_id: order_id.to_string(), trader_id, stock_symbol: symbol, quantity, limit_price })
+    }
+
+    // A request that never reached the broker: forget it and give back any extra cash it held
+    fn abandon_request(&mut self, order_id: &str) {
+        if let Some(tracked) = self.orders.get_mut(order_id) {
+            tracked.pending_cancel = false;
+            tracked.pending_replace = None;
+        }
+        self.trim_reservation(order_id);
+    }
+
+    // Free whatever a limit buy holds beyond its open quantity at its limit price and the most it can be charged on it
+    fn trim_reservation(&mut self, order_id: &str) {
+        let Some(tracked) = self.orders.get_mut(order_id) else { return };
+        let Some(limit_price) = tracked.order.limit_price.filter(|_| tracked.order.order_type.is_buy()) else { return };
+        if tracked.pending_replace.is_some() {
+            return;
+        }
+        let leaves = tracked.leaves_quantity;
+        let excess = tracked.reserved_cash - limit_price * leaves as f64 - self.fee_schedule.most_for(leaves, limit_price);
+        if excess > 0.0 {
+            tracked.reserved_cash -= excess;
+            self.reserved_cash -= excess;
+            self.cash += excess;
+        }
+    }
+
+    // Shares of a symbol not already promised to open sell orders
+    pub fn available_quantity(&self, symbol: &str) -> u32 {
+        let held = u32::try_from(self.position(symbol)).unwrap_or(0);
+        held.saturating_sub(self.promised_quantity(symbol))
+    }
+
+    // Shares of a symbol sold short, plus what open sell orders would sell beyond the shares held
+    pub fn shares_short(&self, symbol: &str) -> u32 {
+        let position = self.position(symbol);
+        let (held, short) = (u32::try_from(position).unwrap_or(0), u32::try_from(-position).unwrap_or(0));
+        short + self.promised_quantity(symbol).saturating_sub(held)
+    }
+
+    // Market order closing `quantity` shares of a position, placed by the broker on an unmet margin call
+    pub fn liquidation_order(&mut self, symbol: &str, quantity: u32) -> Result<OrderRequest, String> {
+        let order_type = if self.position(symbol) > 0 { OrderType::MarketSell } else { OrderType::MarketBuy };
+        let order = Order { order_id: self.generate_order_id(), ..draft_order(self, symbol, order_type, quantity, None, TimeInForce::Day) };
+        self.add_pending_order(order.clone(), 0.0)?;
+        print_colored(&format!("Trader {} is liquidating {} shares of {} to meet a margin call", self.id, quantity, symbol), "red");
+        Ok(OrderRequest::New(order))
+    }
+
+    // Pay the broker for the shares on loan
+    pub fn pay_borrow_fee(&mut self, fee: f64) {
+        self.cash -= fee;
+        self.borrow_fees += fee;
+    }
+
+    fn promised_quantity(&self, symbol: &str) -> u32 {
+        self.orders.promised_quantity(symbol)
+    }
+
+    // Move the reservation for `quantity` just filled shares back to free cash, all of it once the order is closed
+    fn release_reservation(&mut self, order_id: &str, quantity: u32) {
+        let Some(tracked) = self.orders.get_mut(order_id) else { return };
+        let released = if tracked.is_open() {
+            // The reservation covered the filled shares and the ones still open
+            let remaining_before = quantity + tracked.leaves_quantity;
+            tracked.reserved_cash * quantity as f64 / remaining_before.max(1) as f64
+        } else {
+            tracked.reserved_cash
+        };
+        tracked.reserved_cash -= released;
+        self.reserved_cash -= released;
+        self.cash += released;
+    }
+
+    // Apply an execution report. The order tracker decides whether the report is new and legal
+    // before any cash or shares move, so a repeated or stray report leaves the books untouched.
+    pub fn on_status_update(&mut self, update: &OrderStatusUpdate) -> Result<(), String> {
+        // Refused as a copy of an order already sent. The copy was never booked, since an id in use is
+        // refused before it holds anything, so there is nothing of its own to release and the original stays as it is.
+        if update.reject_code == Some(RejectCode::DuplicateOrderId) {
+            print_colored(&format!("Trader {} order {} refused: {}", self.id, update.order_id, update.reason.as_deref().unwrap_or("duplicate order id")), "yellow");
+            return Ok(());
+        }
+        match self.orders.apply(update).map_err(|e| format!("Trader {}: {}", self.id, e))? {
+            Transition::Duplicate => return Ok(()),
+            Transition::Applied => {}
+        }
+        match update.status {
+            OrderStatus::PartiallyFilled | OrderStatus::Filled => self.complete_order(update),
+            OrderStatus::Cancelled | OrderStatus::Rejected | OrderStatus::Expired => {
+                self.cancel_order(&update.order_id, update.leaves_quantity, update.reason.as_deref());
+                Ok(())
+            }
+            OrderStatus::New => Ok(()), // The order is resting in the book, nothing to settle yet
+            OrderStatus::Replaced => {
+                self.trim_reservation(&update.order_id);
+                print_colored(&format!("Trader {} order {} replaced, {} shares of {} open.", self.id, update.order_id, update.leaves_quantity, update.stock_symbol), "yellow");
+                Ok(())
+            }
+            OrderStatus::Triggered => {
+                print_colored(&format!("Trader {} stop order {} on {} triggered.", self.id, update.order_id, update.stock_symbol), "yellow");
+                Ok(())
+            }
+            OrderStatus::CancelRejected => {
+                self.trim_reservation(&update.order_id);
+                print_colored(&format!("Trader {} request on order {} refused: {}", self.id, update.order_id, update.reason.as_deref().unwrap_or("no reason given")), "yellow");
+                Ok(())
+            }
+        }
+    }
+
+    // Settle one fill at the price it actually traded
+    fn complete_order(&mut self, update: &OrderStatusUpdate) -> Result<(), String> {
+        let order = self.orders.get(&update.order_id).map(|tracked| tracked.order.clone())
+            .ok_or_else(|| format!("Trader {} has no pending order {}", self.id, update.order_id))?;
+        let fill_price = update.fill_price
+            .ok_or_else(|| format!("Trader {} received a fill without a price for order {}", self.id, update.order_id))?;
+
+        // The reservation for the filled shares becomes free cash, which then pays the actual fill price
+        self.release_reservation(&order.order_id, update.fill_quantity);
+        if order.order_type.is_buy() {
+            self.buy_stock(&order.stock_symbol, update.fill_quantity, fill_price);
+            print_colored(&format!("Trader {} bought {} shares of {} at ${:.2} each.", self.id, update.fill_quantity, order.stock_symbol, fill_price), "green");
+        } else {
+            self.sell_stock(&order.stock_symbol, update.fill_quantity, fill_price);
+            print_colored(&format!("Trader {} sold {} shares of {} at ${:.2} each.", self.id, update.fill_quantity, order.stock_symbol, fill_price), "red");
+        }
+        // The broker's fees come out of cash on top of the trade itself
+        self.cash -= update.fees;
+        self.ledger.push(Fill {
+            timestamp: update.timestamp,
+            order_id: order.order_id.clone(),
+            symbol: order.stock_symbol.clone(),
+            side: if order.order_type.is_buy() { Side::Buy } else { Side::Sell },
+            quantity: update.fill_quantity,
+            price: fill_price,
+            fees: update.fees,
+        });
         Ok(())
     }
 
+    // Forget an order that never reached the broker and give back what it held
     pub fn remove_pending_order(&mut self, order_id: &str) {
-        self.pending_orders.retain(|o| o.order_id != order_id);
+        // Legs held for an entry that never went out go with it
+        let held_legs = self.orders.group_of(order_id)
+            .filter(|group| group.parent.as_deref() == Some(order_id))
+            .map(|group| group.legs.clone())
+            .unwrap_or_default();
+        for leg in held_legs {
+            self.orders.remove(&leg);
+        }
+        if let Some(tracked) = self.orders.remove(order_id) {
+            self.reserved_cash -= tracked.reserved_cash;
+            self.cash += tracked.reserved_cash;
+        }
     }
 
+    // Free the cash held back for the unfilled quantity of an order the exchange closed
+    fn cancel_order(&mut self, order_id: &str, remaining_quantity: u32, reason: Option<&str>) {
+        self.release_reservation(order_id, 0);
+        if let Some(tracked) = self.orders.get(order_id) {
+            let reason = reason.map(|r| format!(": {}", r)).unwrap_or_default();
+            print_colored(&format!("Trader {} order {} {:?} with {} shares of {} unfilled{}.", self.id, order_id, tracked.state, remaining_quantity, tracked.order.stock_symbol, reason), "yellow");
+        }
+    }
+
+    // Session close: every order still open is cancelled and its reservation freed.
+    // DAY orders have expired by now, what is left is good till cancelled or dated past the close.
     pub fn cancel_pending_orders(&mut self) {
-        let mut rng = rand::thread_rng();
-        let variation = rng.gen_range(0.95..=1.01);
-        let average_cost = 100.0 * variation;
-        for order in &self.pending_orders {
-            match order.order_type {
-                OrderType::MarketBuy | OrderType::LimitBuy => {
-                    let total_cost = order.limit_price.unwrap_or(average_cost) * order.quantity as f64;
-                    self.cash += total_cost;
-                }
-                OrderType::MarketSell | OrderType::LimitSell => {
-                   // Return the quantity of the stock back to the trader's portfolio
-                   if let Some(position) = self.portfolio.iter_mut().find(|p| p.symbol == order.stock_symbol) {
-                    position.quantity += order.quantity;
-                } else {
-                    self.portfolio.push(OwnedPosition {
-                        symbol: order.stock_symbol.clone(),
-                        quantity: order.quantity,
-                        average_cost,
-                    });
-                }
-                }
+        let open: Vec<String> = self.pending_orders().map(|o| o.order_id.clone()).collect();
+        for order_id in open {
+            if self.orders.close(&order_id, OrderState::Cancelled).is_ok() {
+                self.release_reservation(&order_id, 0);
+                print_colored(&format!("Trader {} order {} cancelled at the close, sessions are not persisted.", self.id, order_id), "yellow");
             }
         }
-        self.pending_orders.clear();
+    }
+
+    // Check the books balance: reservations match the open orders, sells never promise more shares
+    // than are held unless the trader sells short, and cash plus the cost of the positions equals
+    // what the trader started with plus the profit booked since, less the fees and borrow fees paid.
+    pub fn reconcile(&self) -> Result<(), String> {
+        let reserved: f64 = self.orders.open_orders().map(|o| o.reserved_cash).sum();
+        if (reserved - self.reserved_cash).abs() > RECONCILE_TOLERANCE * self.initial_value.max(1.0) {
+            return Err(format!("Trader {} reserved cash ${:.6} does not match its open orders ${:.6}", self.id, self.reserved_cash, reserved));
+        }
+        // A short the trader was not allowed was already flagged when the fill was booked
+        for position in self.portfolio.iter().filter(|_| !self.short_selling) {
+            let promised = self.promised_quantity(&position.symbol);
+            if promised as i64 > position.quantity.max(0) {
+                return Err(format!("Trader {} is selling {} shares of {} but holds {}", self.id, promised, position.symbol, position.quantity));
+            }
+        }
+
+        let cost_basis: f64 = self.portfolio.iter().map(|p| p.average_cost * p.quantity as f64).sum();
+        let booked = self.cash + self.reserved_cash + self.short_collateral() + cost_basis;
+        let fees: f64 = self.ledger.iter().map(|fill| fill.fees).sum();
+        let expected = self.initial_value + self.realized_pnl - fees - self.borrow_fees;
+        if (booked - expected).abs() > RECONCILE_TOLERANCE * expected.abs().max(1.0) {
+            return Err(format!("Trader {} cash and positions total ${:.6}, expected ${:.6}", self.id, booked, expected));
+        }
+        Ok(())
     }
 
     fn generate_order_id(&mut self) -> String {
@@ -98,261 +450,304 @@ impl Trader {
     // This function happen on after buying stock
     // This function is to buy new stock existing stock will find the average cost of all quantity 
     // to the stock whereas new stock will be added to the portfolio
-    pub fn buy_stock(&mut self, stock: Stock, quantity: u32) -> Result<(), String> {
-        let total_cost = stock.price * quantity as f64;
-        if self.cash >= total_cost {
-            self.cash -= total_cost;
-            let mut found = false;
-            for held_stock in &mut self.portfolio {
-                if held_stock.symbol == stock.symbol {
-                    let total_quantity = held_stock.quantity + quantity;
-                    held_stock.average_cost = (held_stock.average_cost * held_stock.quantity as f64 + stock.price * quantity as f64) / total_quantity as f64;
-                    held_stock.quantity = total_quantity;
-                    found = true;
-                    break;
-                }
-            }
-            if !found {
-                let new_stock = OwnedPosition {
-                    symbol: stock.symbol.clone(),
-                    quantity,
-                    average_cost: stock.price,
-                };
-                self.portfolio.push(new_stock);
-            }
-            Ok(())
-        } else {
-            Err(format!("Trader {} only have ${:.2} cash, cannot buy {} shares of {}", self.id, self.cash, quantity, stock.symbol))
-        }
-    }
-
-    pub fn sell_stock(&mut self, stock_symbol: &str, quantity: u32) -> Result<f64, String> {
-        let mut found = false;
-        let mut total_revenue = 0.0;
-        for held_stock in &mut self.portfolio {
-            if held_stock.symbol == stock_symbol {
-                if held_stock.quantity >= quantity {
-                    total_revenue = held_stock.average_cost * quantity as f64;
-                    held_stock.quantity -= quantity;
-                    self.cash += total_revenue;
-                    found = true;
-                    break;
-                } else {
-                    return Err(format!("Trader {} does not have enough shares of {}", self.id, stock_symbol));
-                }
+    pub fn buy_stock(&mut self, symbol: &str, quantity: u32, price: f64) {
+        let total_cost = price * quantity as f64;
+        let mut bought = quantity as i64;
+        if let Some(short) = self.portfolio.iter_mut().find(|p| p.symbol == symbol && p.quantity < 0) {
+            // Shares bought back free their collateral and book the profit against the average short price
+            let covered = bought.min(-short.quantity);
+            short.quantity += covered;
+            bought -= covered;
+            self.cash += short.average_cost * covered as f64 * (1.0 + SHORT_MARGIN);
+            self.realized_pnl += (short.average_cost - price) * covered as f64;
+        }
+        if total_cost > self.cash && !self.margin_account {
+            // A market buy can trade above the price its cash was reserved at
+            print_colored(&format!("Trader {} is overdrawn by ${:.2} buying {} shares of {}", self.id, total_cost - self.cash, quantity, symbol), "red");
+        }
+        self.cash -= total_cost;
+        if bought > 0 {
+            let cost = price * bought as f64;
+            if let Some(held_stock) = self.portfolio.iter_mut().find(|p| p.symbol == symbol) {
+                let total_quantity = held_stock.quantity + bought;
+                held_stock.average_cost = (held_stock.average_cost * held_stock.quantity as f64 + cost) / total_quantity as f64;
+                held_stock.quantity = total_quantity;
+            } else {
+                self.portfolio.push(OwnedPosition {
+                    symbol: symbol.to_string(),
+                    quantity: bought,
+                    average_cost: price,
+                });
             }
         }
-        if found {
-            self.portfolio.retain(|stock| stock.quantity > 0);
-            Ok(total_revenue)
-        } else {
-            Err(format!("Trader {} does not own any shares of {}", self.id, stock_symbol))
+        self.portfolio.retain(|stock| stock.quantity != 0);
+    }
+
+    // Credit the proceeds and book the profit against the average cost.
+    // Shares sold beyond those held go short: their proceeds and the margin on them are set aside.
+    // A fill has already happened at the exchange, so it is booked even when the trader may not
+    // sell short, as when both legs of a one-cancels-other group trade before either is cancelled.
+    pub fn sell_stock(&mut self, stock_symbol: &str, quantity: u32, price: f64) {
+        let held = u32::try_from(self.position(stock_symbol)).unwrap_or(0);
+        if !self.short_selling && held < quantity {
+            print_colored(
+                &format!("Trader {} sold {} shares of {} it does not hold, they are booked short", self.id, quantity - held, stock_symbol),
+                "red"
+            );
         }
+        let (sold, shorted) = (quantity.min(held), quantity.saturating_sub(held));
+        let index = match self.portfolio.iter().position(|p| p.symbol == stock_symbol) {
+            Some(index) => index,
+            None => {
+                self.portfolio.push(OwnedPosition { symbol: stock_symbol.to_string(), quantity: 0, average_cost: price });
+                self.portfolio.len() - 1
+            }
+        };
+        let held_stock = &mut self.portfolio[index];
+
+        held_stock.quantity -= sold as i64;
+        self.realized_pnl += (price - held_stock.average_cost) * sold as f64;
+        self.cash += price * sold as f64;
+        if shorted > 0 {
+            let short = -held_stock.quantity as f64;
+            held_stock.average_cost = (held_stock.average_cost * short + price * shorted as f64) / (short + shorted as f64);
+            held_stock.quantity -= shorted as i64;
+            self.cash -= price * shorted as f64 * SHORT_MARGIN;
+        }
+        self.portfolio.retain(|stock| stock.quantity != 0);
     }
 }
 
 
-fn determine_decision(price_change: f64, rng: &mut StdRng) -> Option<u8> {
-    // Default probabilities
-    let mut buy_probability = 0.005;
-    let mut sell_probability = 0.3;
+// An order for the trader with no id yet, one is given once it passes the checks
+fn draft_order(trader: &Trader, symbol: &str, order_type: OrderType, quantity: u32, limit_price: Option<f64>, time_in_force: TimeInForce) -> Order {
+    Order {
+        order_id: String::new(),
+        trader_id: trader.id.clone(),
+        stock_symbol: symbol.to_string(),
+        order_type,
+        quantity,
+        limit_price,
+        stop_price: None,
+        trail: None,
+        time_in_force,
+        display_quantity: None,
+    }
+}
+
+// Price a buy without a limit is held back at: market buys at the last seen price plus a collar, stop buys
+// at their trigger plus the collar since they turn into market orders there. Rounded down to whole cents,
+// the exchange's tick, so a limit at this price never costs more than was held back.
+fn collar_price(order: &Order, last_prices: &HashMap<String, f64>) -> Option<f64> {
+    let trigger = match (order.stop_price, order.trail) {
+        (Some(stop_price), _) => Some(stop_price),
+        (None, Some(trail)) => last_prices.get(&order.stock_symbol).map(|price| trail.stop_from(*price, true)),
+        (None, None) => last_prices.get(&order.stock_symbol).copied(),
+    };
+    trigger.map(|price| (price * (1.0 + MARKET_BUY_COLLAR) * 100.0).floor() / 100.0)
+}
 
-    // Adjust probabilities based on price change
-    if price_change > 0.0 {
-        // Increase sell probability and decrease buy probability if price is rising
-        sell_probability += price_change / 1000.0;
-        buy_probability -= price_change / 1000.0;
-    } else {
-        // Increase buy probability and decrease sell probability if price is falling
-        buy_probability += price_change.abs() / 1000.0;
-        sell_probability -= price_change.abs() / 1000.0;
-    }
-
-    // Generate a random decision based on adjusted probabilities
-    let decision_probability: f64 = rng.gen_range(0.000..1.000);
-    if decision_probability < buy_probability {
-        // Buy decision
-        Some(rng.gen_range(0..=1)) // Market buy or limit buy
-    } else if decision_probability < buy_probability + sell_probability {
-        // Sell decision
-        Some(rng.gen_range(2..=3)) // Market sell or limit sell
+// A cash account never spends more than it holds, so its buys without a limit go out capped at the
+// collar price: a market buy as a limit that trades what it can on arrival, a stop buy as a stop-limit.
+// A trailing stop's trigger keeps moving after its cash is held back, so cash accounts cannot place one.
+fn cap_market_buy(trader: &Trader, order: Order, last_prices: &HashMap<String, f64>) -> Option<Order> {
+    if trader.margin_account {
+        return Some(order);
+    }
+    let (order_type, time_in_force) = match order.order_type {
+        OrderType::MarketBuy => (OrderType::LimitBuy, TimeInForce::Ioc),
+        OrderType::StopBuy => (OrderType::StopLimitBuy, order.time_in_force),
+        OrderType::TrailingStopBuy => {
+            print_colored(&format!("Trader {} cannot place a trailing stop buy without a margin account", trader.id), "yellow");
+            return None;
+        }
+        _ => return Some(order),
+    };
+    let limit_price = collar_price(&order, last_prices)?;
+    Some(Order { order_type, time_in_force, limit_price: Some(limit_price), ..order })
+}
+
+// Cash a new order holds back if the trader can cover it: buys need the cash, sells need shares
+// not already promised to other sell orders, or the margin on the rest when the trader sells short
+fn order_cost(trader: &Trader, order: &Order, last_prices: &HashMap<String, f64>) -> Option<f64> {
+    if order.order_type.is_buy() {
+        let price = order.limit_price.or_else(|| collar_price(order, last_prices))?;
+        // The fees come out of cash on top of the shares, so the most they can be is held back too
+        let total_cost = price * order.quantity as f64 + trader.fee_schedule.most_for(order.quantity, price);
+        cash_for(trader, total_cost)
     } else {
-        // Hold decision
+        let available = trader.available_quantity(&order.stock_symbol);
+        if available >= order.quantity {
+            return Some(0.0);
+        }
+        if !trader.short_selling {
+            print_colored(&format!("Trader {} does not have enough shares of {} to sell", trader.id, order.stock_symbol), "yellow");
+            return None;
+        }
+        // The shares sold short hold back their margin, priced at the limit or trigger, otherwise the last price
+        let price = order.limit_price.or(order.stop_price).or_else(|| last_prices.get(&order.stock_symbol).copied())?;
+        cash_for(trader, price * (order.quantity - available) as f64 * SHORT_MARGIN)
+    }
+}
+
+// Cash held back towards `cost`: all of it, or what cash covers in a margin account since the
+// broker lends the rest once it has checked the buying power
+fn cash_for(trader: &Trader, cost: f64) -> Option<f64> {
+    if trader.margin_account {
+        Some(cost.min(trader.cash.max(0.0)))
+    } else if trader.cash < cost {
         None
+    } else {
+        Some(cost)
+    }
+}
+
+fn describe_order(order: &Order) -> String {
+    let (trader, symbol, quantity) = (&order.trader_id, &order.stock_symbol, order.quantity);
+    let side = if order.order_type.is_buy() { "buy" } else { "sell" };
+    let iceberg = order.display_quantity.filter(|display| *display < quantity)
+        .map(|display| format!(", showing {} of {} shares", display, quantity))
+        .unwrap_or_default();
+    let description = match order.order_type {
+        OrderType::MarketBuy => format!("Trader {} sent market order to buy {} shares of {}", trader, quantity, symbol),
+        OrderType::LimitBuy => format!("Trader {} decided to limit buy stock: {} at price ${:.2}", trader, symbol, order.limit_price.unwrap_or(0.0)),
+        OrderType::MarketSell => format!("Trader {} decided to market sell {} shares of {}", trader, quantity, symbol),
+        OrderType::LimitSell => format!("Trader {} decided to limit sell stock: {} at price ${:.2}", trader, symbol, order.limit_price.unwrap_or(0.0)),
+        OrderType::StopBuy | OrderType::StopSell => format!(
+            "Trader {} placed a stop to {} {} shares of {} at ${:.2}",
+            trader, side, quantity, symbol, order.stop_price.unwrap_or(0.0)
+        ),
+        OrderType::StopLimitBuy | OrderType::StopLimitSell => format!(
+            "Trader {} placed a stop-limit to {} {} shares of {} at ${:.2} limit ${:.2}",
+            trader, side, quantity, symbol, order.stop_price.unwrap_or(0.0), order.limit_price.unwrap_or(0.0)
+        ),
+        OrderType::TrailingStopBuy | OrderType::TrailingStopSell => format!(
+            "Trader {} placed a trailing stop to {} {} shares of {} trailing by {}",
+            trader, side, quantity, symbol,
+            match order.trail {
+                Some(Trail::Amount(amount)) => format!("${:.2}", amount),
+                Some(Trail::Percent(percent)) => format!("{}%", percent),
+                None => "nothing".to_string(),
+            }
+        ),
+    };
+    description + &iceberg
+}
+
+// Take-profit limit sell and stop-loss sell for the same shares, good till cancelled
+fn exit_legs(trader: &mut Trader, symbol: &str, quantity: u32, take_profit: f64, stop_loss: f64) -> Vec<Order> {
+    let take_profit = draft_order(trader, symbol, OrderType::LimitSell, quantity, Some(take_profit), TimeInForce::Gtc);
+    let stop_loss = Order { stop_price: Some(stop_loss), ..draft_order(trader, symbol, OrderType::StopSell, quantity, None, TimeInForce::Gtc) };
+    [take_profit, stop_loss].into_iter().map(|leg| Order { order_id: trader.generate_order_id(), ..leg }).collect()
+}
+
+// Turn a strategy's intent into orders if the trader can cover them, booking them on the trader.
+// Returns the requests to send to the broker.
+fn submit_intent(trader: &mut Trader, intent: OrderIntent, last_prices: &HashMap<String, f64>) -> Vec<OrderRequest> {
+    let order = match intent {
+        OrderIntent::Submit { symbol, order_type, quantity, limit_price, time_in_force, display_quantity } => {
+            Order { display_quantity, ..draft_order(trader, &symbol, order_type, quantity, limit_price, time_in_force) }
+        }
+        OrderIntent::SubmitStop { symbol, order_type, quantity, limit_price, stop_price, trail, time_in_force } => {
+            Order { stop_price, trail, ..draft_order(trader, &symbol, order_type, quantity, limit_price, time_in_force) }
+        }
+        OrderIntent::Cancel { order_id } => {
+            return trader.request_cancel(&order_id).into_iter().collect();
+        }
+        OrderIntent::Replace { order_id, quantity, limit_price } => {
+            return trader.request_replace(&order_id, quantity, limit_price).into_iter().collect();
+        }
+        OrderIntent::Bracket { symbol, quantity, limit_price, take_profit, stop_loss } => {
+            if quantity == 0 || stop_loss <= 0.0 || stop_loss >= take_profit {
+                return Vec::new();
+            }
+            let order_type = if limit_price.is_some() { OrderType::LimitBuy } else { OrderType::MarketBuy };
+            let entry = draft_order(trader, &symbol, order_type, quantity, limit_price, TimeInForce::Day);
+            let Some(entry) = cap_market_buy(trader, entry, last_prices) else { return Vec::new() };
+            let Some(total_cost) = order_cost(trader, &entry, last_prices) else { return Vec::new() };
+            let entry = Order { order_id: trader.generate_order_id(), ..entry };
+            let legs = exit_legs(trader, &symbol, quantity, take_profit, stop_loss);
+            if let Err(e) = trader.add_order_group(Some((entry.clone(), total_cost)), legs) {
+                print_colored(&format!("Trader {} could not place bracket: {}", trader.id, e), "red");
+                return Vec::new();
+            }
+            print_colored(
+                &format!("Trader {} placed a bracket to buy {} shares of {}, take profit ${:.2}, stop loss ${:.2}", trader.id, quantity, symbol, take_profit, stop_loss),
+                "green"
+            );
+            return vec![OrderRequest::New(entry)];
+        }
+        OrderIntent::Oco { symbol, quantity, take_profit, stop_loss } => {
+            if quantity == 0 || stop_loss <= 0.0 || stop_loss >= take_profit || trader.available_quantity(&symbol) < quantity {
+                return Vec::new();
+            }
+            let legs = exit_legs(trader, &symbol, quantity, take_profit, stop_loss);
+            if let Err(e) = trader.add_order_group(None, legs.clone()) {
+                print_colored(&format!("Trader {} could not place exits: {}", trader.id, e), "red");
+                return Vec::new();
+            }
+            print_colored(
+                &format!("Trader {} placed one-cancels-other exits on {} shares of {}, take profit ${:.2}, stop loss ${:.2}", trader.id, quantity, symbol, take_profit, stop_loss),
+                "magenta"
+            );
+            return legs.into_iter().map(OrderRequest::New).collect();
+        }
+    };
+    if order.quantity == 0 {
+        return Vec::new();
     }
+    let Some(mut order) = cap_market_buy(trader, order, last_prices) else { return Vec::new() };
+    let Some(total_cost) = order_cost(trader, &order, last_prices) else { return Vec::new() };
+    order.order_id = trader.generate_order_id();
+
+    // Add pending order, holding back its cash
+    if let Err(e) = trader.add_pending_order(order.clone(), total_cost) {
+        print_colored(&format!("Trader {} could not place order: {}", trader.id, e), "red");
+        return Vec::new();
+    }
+    print_colored(&describe_order(&order), if order.order_type.is_buy() { "green" } else { "magenta" });
+
+    vec![OrderRequest::New(order)]
 }
 
+// Send the requests to the broker without holding the trader, whose lock the broker needs to settle orders
+async fn send_requests(trader: &Mutex<Trader>, requests: Vec<OrderRequest>, order_tx: &mpsc::Sender<OrderRequest>) {
+    for request in requests {
+        if let Err(mpsc::error::SendError(request)) = order_tx.send(request).await {
+            let mut trader = trader.lock().await;
+            print_colored(&format!("Trader {} failed to send order: channel closed", trader.id), "red");
+            // If sending fails, undo whatever the request reserved
+            match request {
+                OrderRequest::New(order) => trader.remove_pending_order(&order.order_id),
+                OrderRequest::Cancel { order_id, .. } | OrderRequest::Replace { order_id, .. } => trader.abandon_request(&order_id),
+            }
+        }
+    }
+}
 
-pub async fn run_trader(
+pub async fn run_trader<S: Strategy>(
     trader_id: String,
     mut stock_rx: mpsc::Receiver<Stock>,
-    order_tx: mpsc::Sender<Order>,
+    order_tx: mpsc::Sender<OrderRequest>,
     trader: Arc<Mutex<Trader>>, // Pass the trader as an Arc<Mutex<Trader>>
+    mut strategy: S,
 ) {
     println!("Trader {} ready to trade.", trader_id);
-    // This is for any random number generation
-    let rng = Arc::new(Mutex::new(StdRng::from_entropy()));
+    // Latest price of every symbol seen so far, used to price market orders
+    let mut last_prices: HashMap<String, f64> = HashMap::new();
 
     loop {
         match stock_rx.recv().await {
             Some(stock) => {
-                // println!(
-                //     "Trader {} received stock update: Symbol: {}, Price: ${:.2}",
-                //     trader_id, stock.symbol, stock.price
-                // );
-                
-                
-                // Simulate decision-making (e.g., market buy/limit buy/hold/market sell/limit sell)
-                let mut rng = rng.lock().await;
-                if let Some(decision) = determine_decision(stock.price_change.percentage, &mut rng) {
-                    match decision {
-                        0 => {
-                            // Market buy
-                            print_colored(
-                                &format!("Trader {} decided to buy stock: {} on ${:.2}", trader_id, stock.symbol, stock.price),
-                                "cyan"
-                            );
-                            let quantity = rng.gen_range(1..=3);
-                            let total_cost = stock.price * quantity as f64;
-                            let mut trader = trader.lock().await;
-                            if trader.cash >= total_cost {
-                                let order_id = trader.generate_order_id();
-                                let order = Order {
-                                    order_id,
-                                    trader_id: trader_id.clone(),
-                                    stock_symbol: stock.symbol.clone(),
-                                    order_type: OrderType::MarketBuy,
-                                    quantity,
-                                    limit_price: None,
-                                };
-                                // Add pending order
-                                trader.add_pending_order(order.clone());
-                                // Deduct cash
-                                trader.cash -= total_cost;
-                                print_colored(
-                                    &format!("Trader {} sent market order to buy stock: {} on ${:.2}", trader_id, stock.symbol, stock.price),
-                                    "green"
-                                );
-                                // Send the order to the broker
-                                if let Err(e) = order_tx.send(order).await {
-                                    print_colored(&format!("Trader {} failed to send order: {:?}", trader_id, e), "red");
-                                }
-                            } else {
-                                // print_colored(&format!("Trader {} does not have enough cash to buy {} shares of {}", trader_id, quantity, stock.symbol), "red");
-                            }
-                        }
-                        1 => {
-                            // Limit buy
-                            let limit_price = rng.gen_range(stock.price * 0.95..=stock.price);
-                            print_colored(
-                                &format!("Trader {} decided to limit buy stock: {} at price ${:.2}", trader_id, stock.symbol, limit_price),
-                                "cyan"
-                            );
-                            let quantity = rng.gen_range(1..=3);
-                            let total_cost = limit_price * quantity as f64;
-                            let mut trader = trader.lock().await;
-                            if trader.cash >= total_cost {
-                                let order_id = trader.generate_order_id();
-                                let order = Order {
-                                    order_id:order_id.clone(),
-                                    trader_id: trader_id.clone(),
-                                    stock_symbol: stock.symbol.clone(),
-                                    order_type: OrderType::LimitBuy,
-                                    quantity,
-                                    limit_price: Some(limit_price),
-                                };
-                                // Add pending order
-                                trader.add_pending_order(order.clone());
-                                // Deduct cash
-                                trader.cash -= total_cost;
-                                // Send the order to the broker
-                                if let Err(e) = order_tx.send(order).await {
-                                    print_colored(&format!("Trader {} failed to send order: {:?}", trader_id, e), "red");
-                                    // If sending the order fails, remove it from pending orders
-                                    trader.remove_pending_order(&order_id);
-                                }
-                            } else {
-                                // print_colored(&format!("Trader {} does not have enough cash to buy {} shares of {}", trader_id, quantity, stock.symbol), "red");
-                            }
-                        }
-                        2 => {
-                            // Market sell
-                            let mut trader = trader.lock().await;
-                            if let Some(position) = trader.portfolio.iter().find(|p| p.symbol == stock.symbol) {
-                                if position.quantity > 0 {
-                                    let quantity = rng.gen_range(1..=position.quantity);
-                                    let order_id = trader.generate_order_id();
-                                    let order = Order {
-                                        order_id: order_id.clone(),
-                                        trader_id: trader_id.clone(),
-                                        stock_symbol: stock.symbol.clone(),
-                                        order_type: OrderType::MarketSell,
-                                        quantity,
-                                        limit_price: None,
-                                    };
-                                    // Add pending order
-                                    trader.add_pending_order(order.clone());
-                                    print_colored(
-                                        &format!("Trader {} decided to market sell {} shares of {}", trader_id, quantity, stock.symbol),
-                                        "magenta"
-                                    );
-                                    // Send the order to the broker
-                                    if let Err(e) = order_tx.send(order).await {
-                                        print_colored(&format!("Trader {} failed to send order: {:?}", trader_id, e), "red");
-                                        // If sending the order fails, remove it from pending orders
-                                        trader.remove_pending_order(&order_id);
-                                    }
-                                } else {
-                                    print_colored(&format!("Trader {} does not have enough shares of {} to sell", trader_id, stock.symbol), "yellow");
-                                }
-                            } else {
-                                // print_colored(&format!("Trader {} does not own any shares of {}", trader_id, stock.symbol), "yellow");
-                            }
-                        }
-                        3 => {
-                            // Limit sell
-                            let mut trader = trader.lock().await;
-                            if let Some(position) = trader.portfolio.iter().find(|p| p.symbol == stock.symbol) {
-                                if position.quantity > 0 {
-                                    let limit_price = rng.gen_range(stock.price..=stock.price * 1.05);
-                                    let quantity = rng.gen_range(1..=position.quantity);
-                                    let order_id = trader.generate_order_id();
-                                    let order = Order {
-                                        order_id: order_id.clone(),
-                                        trader_id: trader_id.clone(),
-                                        stock_symbol: stock.symbol.clone(),
-                                        order_type: OrderType::LimitSell,
-                                        quantity,
-                                        limit_price: Some(limit_price),
-                                    };
-                                    // Add pending order
-                                    trader.add_pending_order(order.clone());
-                                    print_colored(
-                                        &format!("Trader {} decided to limit sell stock: {} at price ${:.2}", trader_id, stock.symbol, limit_price),
-                                        "magenta"
-                                    );
-                                    // Send the order to the broker
-                                    if let Err(e) = order_tx.send(order).await {
-                                        print_colored(&format!("Trader {} failed to send order: {:?}", trader_id, e), "red");
-                                        // If sending the order fails, remove it from pending orders
-                                        trader.remove_pending_order(&order_id);
-                                    }
-                                } else {
-                                    print_colored(&format!("Trader {} does not have enough shares of {} to sell", trader_id, stock.symbol), "yellow");
-                                }
-                            } else {
-                                // print_colored(&format!("Trader {} does not own any shares of {}", trader_id, stock.symbol), "yellow");
-                            }
-                        }
-                        _ => {}
-                    }
-                } else {
-                    // Hold decision
-                    // print_colored(
-                    //     &format!("Trader {} decided to hold stock: {}", trader_id, stock.symbol),
-                    //     "yellow"
-                    // );
-                }
+                last_prices.insert(stock.symbol.clone(), stock.price);
+
+                // Let the strategy decide and book whatever it asked for, then send it once the trader is free
+                let requests: Vec<OrderRequest> = {
+                    let mut guard = trader.lock().await;
+                    let intents = strategy.on_market_update(&stock, &guard);
+                    intents.into_iter().flat_map(|intent| submit_intent(&mut guard, intent, &last_prices)).collect()
+                };
+                send_requests(&trader, requests, &order_tx).await;
             }
             None => {
                 println!("Trader {} stock channel closed.", trader_id);
@@ -360,4 +755,140 @@ pub async fn run_trader(
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit_buy(trader: &mut Trader, quantity: u32, limit_price: f64) -> Order {
+        let order = Order {
+            order_id: trader.generate_order_id(),
+            ..draft_order(trader, "AAPL", OrderType::LimitBuy, quantity, Some(limit_price), TimeInForce::Day)
+        };
+        trader.add_pending_order(order.clone(), limit_price * quantity as f64).unwrap();
+        order
+    }
+
+    fn report(order: &Order, status: OrderStatus, fill: Option<(u32, f64)>, cumulative_quantity: u32, leaves_quantity: u32) -> OrderStatusUpdate {
+        OrderStatusUpdate {
+            order_id: order.order_id.clone(),
+            trader_id: order.trader_id.clone(),
+            stock_symbol: order.stock_symbol.clone(),
+            status,
+            fill_quantity: fill.map_or(0, |(quantity, _)| quantity),
+            fill_price: fill.map(|(_, price)| price),
+            cumulative_quantity,
+            leaves_quantity,
+            timestamp: 0,
+            reason: None,
+            liquidity: None,
+            fees: 0.0,
+            reject_code: None,
+        }
+    }
+
+    fn assert_balanced(trader: &Trader) {
+        assert_eq!(trader.reconcile(), Ok(()));
+    }
+
+    #[test]
+    fn reservations_balance_through_an_orders_life() {
+        let mut trader = Trader::new("T".to_string(), 1000.0);
+
+        let order = limit_buy(&mut trader, 10, 50.0);
+        assert_balanced(&trader);
+        assert_eq!(trader.reserved_cash, 500.0);
+        assert_eq!(trader.cash, 500.0);
+
+        trader.on_status_update(&report(&order, OrderStatus::New, None, 0, 10)).unwrap();
+        trader.on_status_update(&report(&order, OrderStatus::PartiallyFilled, Some((4, 49.0)), 4, 6)).unwrap();
+        assert_balanced(&trader);
+        assert!((trader.reserved_cash - 300.0).abs() < 1e-9);
+        assert_eq!(trader.position("AAPL"), 4);
+
+        trader.on_status_update(&report(&order, OrderStatus::Cancelled, None, 4, 6)).unwrap();
+        assert_balanced(&trader);
+        assert_eq!(trader.reserved_cash, 0.0);
+        assert!((trader.cash - (1000.0 - 4.0 * 49.0)).abs() < 1e-9);
+
+        let order = limit_buy(&mut trader, 5, 40.0);
+        trader.on_status_update(&report(&order, OrderStatus::New, None, 0, 5)).unwrap();
+        trader.on_status_update(&report(&order, OrderStatus::Expired, None, 0, 5)).unwrap();
+        assert_balanced(&trader);
+        assert_eq!(trader.reserved_cash, 0.0);
+
+        let order = limit_buy(&mut trader, 2, 45.0);
+        assert_balanced(&trader);
+        trader.on_status_update(&report(&order, OrderStatus::Rejected, None, 0, 2)).unwrap();
+        assert_balanced(&trader);
+        assert_eq!(trader.reserved_cash, 0.0);
+        assert_eq!(trader.pending_orders().count(), 0);
+    }
+
+    #[test]
+    fn repeated_reports_leave_the_books_untouched() {
+        let mut trader = Trader::new("T".to_string(), 1000.0);
+        let order = limit_buy(&mut trader, 10, 50.0);
+        let fill = report(&order, OrderStatus::PartiallyFilled, Some((4, 50.0)), 4, 6);
+        trader.on_status_update(&fill).unwrap();
+        let cash = trader.cash;
+        trader.on_status_update(&fill).unwrap();
+        assert_eq!(trader.cash, cash);
+        assert_eq!(trader.position("AAPL"), 4);
+        assert_eq!(trader.ledger.len(), 1);
+        assert_balanced(&trader);
+
+        trader.on_status_update(&report(&order, OrderStatus::Filled, Some((6, 50.0)), 10, 0)).unwrap();
+        trader.on_status_update(&report(&order, OrderStatus::Cancelled, None, 10, 0)).unwrap();
+        assert_balanced(&trader);
+        assert_eq!(trader.reserved_cash, 0.0);
+        assert_eq!(trader.position("AAPL"), 10);
+    }
+
+    #[test]
+    fn a_second_order_under_the_same_id_is_refused() {
+        let mut trader = Trader::new("T".to_string(), 1000.0);
+        let order = limit_buy(&mut trader, 10, 50.0);
+        trader.on_status_update(&report(&order, OrderStatus::New, None, 0, 10)).unwrap();
+
+        // Booking the copy fails before it holds any cash
+        let copy = Order { quantity: 4, ..order.clone() };
+        assert!(trader.add_pending_order(copy.clone(), 200.0).is_err());
+        assert_eq!(trader.reserved_cash, 500.0);
+        assert_balanced(&trader);
+
+        // The broker's refusal of the copy leaves the original open with its reservation
+        let refusal = OrderStatusUpdate {
+            reject_code: Some(RejectCode::DuplicateOrderId),
+            ..report(&copy, OrderStatus::Rejected, None, 0, 4)
+        };
+        trader.on_status_update(&refusal).unwrap();
+        assert_eq!(trader.reserved_cash, 500.0);
+        assert_eq!(trader.pending_orders().count(), 1);
+        assert_balanced(&trader);
+
+        trader.on_status_update(&report(&order, OrderStatus::Filled, Some((10, 50.0)), 10, 0)).unwrap();
+        assert_balanced(&trader);
+        assert_eq!(trader.reserved_cash, 0.0);
+        assert_eq!(trader.position("AAPL"), 10);
+    }
+
+    #[test]
+    fn a_cash_account_caps_its_market_buys_at_the_cash_held_back() {
+        let mut trader = Trader::new("T".to_string(), 1000.0);
+        let last_prices = HashMap::from([("AAPL".to_string(), 10.0)]);
+        let market = draft_order(&trader, "AAPL", OrderType::MarketBuy, 10, None, TimeInForce::Day);
+        let requests = submit_intent(&mut trader, OrderIntent::Submit {
+            symbol: "AAPL".to_string(), order_type: OrderType::MarketBuy, quantity: 10, limit_price: None, time_in_force: TimeInForce::Day, display_quantity: None,
+        }, &last_prices);
+        let [OrderRequest::New(order)] = requests.as_slice() else { panic!("expected one new order") };
+        assert!(matches!((&order.order_type, &order.time_in_force), (OrderType::LimitBuy, TimeInForce::Ioc)));
+        assert_eq!(order.limit_price, Some(10.5));
+        assert!((trader.reserved_cash - 105.0).abs() < 1e-9);
+        assert_balanced(&trader);
+
+        let trailing = Order { trail: Some(Trail::Percent(5.0)), order_type: OrderType::TrailingStopBuy, ..market };
+        assert!(cap_market_buy(&trader, trailing, &last_prices).is_none());
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
+use lapin::{
+    options::*, types::FieldTable, BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind,
+};
+use tokio::sync::{mpsc, Mutex};
+
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+pub type TransportResult<T> = Result<T, TransportError>;
+
+type Acker = Box<dyn FnOnce() -> BoxFuture<'static, TransportResult<()>> + Send>;
+
+// A message taken from a queue. It must be acknowledged once it has been processed.
+pub struct Delivery {
+    pub data: Vec<u8>,
+    acker: Option<Acker>,
+}
+
+impl Delivery {
+    pub async fn ack(self) -> TransportResult<()> {
+        match self.acker {
+            Some(acker) => acker().await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub type Subscription = BoxStream<'static, TransportResult<Delivery>>;
+
+// Messaging operations shared by every market component.
+// Publishing to the empty exchange delivers straight to the queue named by the routing key,
+// publishing to a named exchange fans the message out to every queue bound to it.
+pub trait Transport: Send + Sync {
+    fn publish<'a>(&'a self, exchange: &'a str, routing_key: &'a str, payload: &'a [u8]) -> BoxFuture<'a, TransportResult<()>>;
+
+    // Consume from `queue`, binding it to `exchange` first when one is given.
+    // An empty queue name creates a private queue for this subscriber only.
+    fn subscribe<'a>(&'a self, exchange: &'a str, queue: &'a str) -> BoxFuture<'a, TransportResult<Subscription>>;
+}
+
+// RabbitMQ backed transport sharing a single connection between all components
+pub struct AmqpTransport {
+    connection: Connection,
+    channel: Channel,
+    declared: Mutex<HashSet<String>>,
+}
+
+impl AmqpTransport {
+    pub async fn connect(url: &str) -> TransportResult<Self> {
+        let connection = Connection::connect(url, ConnectionProperties::default()).await?;
+        let channel = connection.create_channel().await?;
+        Ok(Self { connection, channel, declared: Mutex::new(HashSet::new()) })
+    }
+
+    // Declare queues and exchanges once, so messages are not dropped on a fresh RabbitMQ server
+    async fn declare(&self, exchange: &str, queue: &str) -> TransportResult<()> {
+        let mut declared = self.declared.lock().await;
+        if !exchange.is_empty() && declared.insert(format!("exchange:{}", exchange)) {
+            self.channel.exchange_declare(
+                exchange,
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions::default(),
+                FieldTable::default(),
+            ).await?;
+        }
+        if !queue.is_empty() && declared.insert(format!("queue:{}", queue)) {
+            self.channel.queue_declare(queue, QueueDeclareOptions::default(), FieldTable::default()).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for AmqpTransport {
+    fn publish<'a>(&'a self, exchange: &'a str, routing_key: &'a str, payload: &'a [u8]) -> BoxFuture<'a, TransportResult<()>> {
+        async move {
+            if exchange.is_empty() {
+                self.declare("", routing_key).await?;
+            } else {
+                self.declare(exchange, "").await?;
+            }
+            self.channel.basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                payload,
+                BasicProperties::default(),
+            ).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn subscribe<'a>(&'a self, exchange: &'a str, queue: &'a str) -> BoxFuture<'a, TransportResult<Subscription>> {
+        async move {
+            // Each subscriber gets its own channel so a slow consumer does not hold up the others
+            let channel = self.connection.create_channel().await?;
+            let queue_name = if queue.is_empty() {
+                // Server named queue that lives only as long as this subscriber
+                let options = QueueDeclareOptions { exclusive: true, auto_delete: true, ..QueueDeclareOptions::default() };
+                channel.queue_declare("", options, FieldTable::default()).await?.name().to_string()
+            } else {
+                self.declare("", queue).await?;
+                queue.to_string()
+            };
+            if !exchange.is_empty() {
+                self.declare(exchange, "").await?;
+                channel.queue_bind(&queue_name, exchange, "", QueueBindOptions::default(), FieldTable::default()).await?;
+            }
+
+            let consumer = channel.basic_consume(&queue_name, "", BasicConsumeOptions::default(), FieldTable::default()).await?;
+            let subscription = consumer.map(|delivery| {
+                let delivery = delivery?;
+                let acker = delivery.acker.clone();
+                Ok(Delivery {
+                    data: delivery.data,
+                    acker: Some(Box::new(move || async move {
+                        acker.ack(BasicAckOptions::default()).await?;
+                        Ok(())
+                    }.boxed()) as Acker),
+                })
+            });
+            Ok(subscription.boxed())
+        }.boxed()
+    }
+}
+
+#[derive(Clone)]
+struct MemoryQueue {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: Arc<Mutex<mpsc::UnboundedReceiver<Vec<u8>>>>,
+    private: bool, // Named for one subscriber and deleted once it is gone
+}
+
+impl MemoryQueue {
+    fn new() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self { tx, rx: Arc::new(Mutex::new(rx)), private: false }
+    }
+
+    // Only the transport's own handle is left once the subscriber has dropped its stream
+    fn is_abandoned(&self) -> bool {
+        self.private && Arc::strong_count(&self.rx) == 1
+    }
+}
+
+#[derive(Default)]
+struct MemoryState {
+    queues: HashMap<String, MemoryQueue>,
+    bindings: HashMap<String, Vec<String>>,
+    private_queues: u64,
+}
+
+impl MemoryState {
+    // Delete the private queues bound to an exchange whose subscriber has gone, like RabbitMQ's
+    // exclusive queues, so publishing does not keep filling queues nobody reads
+    fn drop_abandoned(&mut self, exchange: &str) {
+        let Some(bound) = self.bindings.get_mut(exchange) else { return };
+        let queues = &mut self.queues;
+        bound.retain(|name| match queues.get(name) {
+            Some(queue) if queue.is_abandoned() => {
+                queues.remove(name);
+                false
+            }
+            _ => true,
+        });
+    }
+}
+
+// In-process transport built on tokio channels, for running the whole market without a broker.
+// Consumers sharing a queue compete for its messages, exactly like they do on RabbitMQ.
+#[derive(Default)]
+pub struct InMemoryTransport {
+    state: std::sync::Mutex<MemoryState>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn publish<'a>(&'a self, exchange: &'a str, routing_key: &'a str, payload: &'a [u8]) -> BoxFuture<'a, TransportResult<()>> {
+        let mut state = self.state.lock().unwrap();
+        let targets: Vec<MemoryQueue> = if exchange.is_empty() {
+            vec![state.queues.entry(routing_key.to_string()).or_insert_with(MemoryQueue::new).clone()]
+        } else {
+            // Messages published to an exchange without bound queues are dropped
+            state.drop_abandoned(exchange);
+            state.bindings.get(exchange)
+                .map(|queues| queues.iter().filter_map(|name| state.queues.get(name).cloned()).collect())
+                .unwrap_or_default()
+        };
+        drop(state);
+
+        let result = targets.iter()
+            .try_for_each(|queue| queue.tx.send(payload.to_vec()))
+            .map_err(|e| e.into());
+        futures::future::ready(result).boxed()
+    }
+
+    fn subscribe<'a>(&'a self, exchange: &'a str, queue: &'a str) -> BoxFuture<'a, TransportResult<Subscription>> {
+        let mut state = self.state.lock().unwrap();
+        let (queue_name, private) = if queue.is_empty() {
+            state.private_queues += 1;
+            (format!("private.{}", state.private_queues), true)
+        } else {
+            (queue.to_string(), false)
+        };
+        let memory_queue = state.queues.entry(queue_name.clone())
+            .or_insert_with(|| MemoryQueue { private, ..MemoryQueue::new() })
+            .clone();
+        if !exchange.is_empty() {
+            let bound = state.bindings.entry(exchange.to_string()).or_default();
+            if !bound.contains(&queue_name) {
+                bound.push(queue_name);
+            }
+        }
+        drop(state);
+
+        let subscription = futures::stream::unfold(memory_queue.rx, |rx| async move {
+            let data = rx.lock().await.recv().await?;
+            Some((Ok(Delivery { data, acker: None }), rx))
+        });
+        futures::future::ready(Ok(subscription.boxed())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_private_queue_goes_once_its_subscriber_does() {
+        let transport = InMemoryTransport::new();
+        let mut kept = transport.subscribe("prices", "").await.unwrap();
+        let dropped = transport.subscribe("prices", "").await.unwrap();
+        drop(dropped);
+
+        transport.publish("prices", "", b"tick").await.unwrap();
+        assert_eq!(kept.next().await.unwrap().unwrap().data, b"tick");
+        let state = transport.state.lock().unwrap();
+        assert_eq!(state.queues.keys().collect::<Vec<_>>(), ["private.1"]);
+        assert_eq!(state.bindings["prices"], ["private.1"]);
+    }
+}